@@ -1,2 +1,2 @@
 #!/bin/bash
-exec cargo run --release --quiet 2>/dev/null
\ No newline at end of file
+exec cargo run --release --quiet --bin amarillo -- "$@" 2>/dev/null
\ No newline at end of file
@@ -0,0 +1,116 @@
+use amarillo::game_state::*;
+use amarillo::packed::PackedState;
+use amarillo::rules::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "playouts",
+    about = "Measures how many random playouts per second are run on a State and on a PackedState."
+)]
+struct Opt {
+    /// Number of players (2 to 4).
+    #[structopt(short, long, default_value = "3")]
+    players: u8,
+    /// Seconds to run the playouts for, with each representation.
+    #[structopt(long, default_value = "3")]
+    seconds: u64,
+    /// Seed for the random number generator.
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+    /// Play the free placement ("grey wall") variant.
+    #[structopt(long)]
+    free_wall: bool,
+}
+
+/// Positions at the start of a round, from random games.
+fn start_positions(num_players: u8, rules: Arc<RuleSet>, rng: &mut SmallRng) -> Vec<State> {
+    let mut positions = Vec::new();
+    while positions.len() < 100 {
+        let mut state = get_random_initial_state(num_players, rules.clone(), rng);
+        positions.push(state.clone());
+        while !state.is_finished {
+            let valid_actions = get_valid_actions(&state);
+            let action = valid_actions[rng.gen_range(0, valid_actions.len())];
+            let (next_state, round_over) = step(state, action, false);
+            state = next_state;
+            if round_over && !state.is_finished {
+                fill_factory_displays(&mut state, rng);
+                positions.push(state.clone());
+            }
+        }
+    }
+    positions
+}
+
+/// Runs playouts to the end of the round for `duration`, returning the number per second.
+fn measure<F: FnMut(&State, &mut SmallRng)>(
+    positions: &[State],
+    duration: Duration,
+    rng: &mut SmallRng,
+    mut playout: F,
+) -> f64 {
+    let start = Instant::now();
+    let mut num_playouts = 0;
+    while start.elapsed() < duration {
+        playout(&positions[num_playouts % positions.len()], rng);
+        num_playouts += 1;
+    }
+    num_playouts as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(err) = check_num_players(opt.players) {
+        eprintln!("{}.", err);
+        std::process::exit(1);
+    }
+    let mut rules = RuleSet::default();
+    rules.free_wall = opt.free_wall;
+    let rules = Arc::new(rules);
+    let mut rng = SmallRng::seed_from_u64(opt.seed);
+    let positions = start_positions(opt.players, rules.clone(), &mut rng);
+    let duration = Duration::from_secs(opt.seconds);
+
+    let state_rate = measure(&positions, duration, &mut rng, |state, rng| {
+        let mut state = state.clone();
+        loop {
+            let valid_actions = get_valid_actions(&state);
+            if valid_actions.is_empty() {
+                break;
+            }
+            let action = valid_actions[rng.gen_range(0, valid_actions.len())];
+            let (next_state, round_over) = step(state, action, false);
+            state = next_state;
+            if round_over {
+                break;
+            }
+        }
+    });
+    println!("State:       {:.0} playouts/s", state_rate);
+
+    let packed_rate = measure(&positions, duration, &mut rng, |state, rng| {
+        let mut packed = PackedState::from_state(state).unwrap();
+        let mut valid_actions = ActionBuffer::new();
+        loop {
+            packed.fill_valid_actions(&mut valid_actions);
+            if valid_actions.is_empty() {
+                break;
+            }
+            let action = valid_actions[rng.gen_range(0, valid_actions.len())];
+            if packed.step(action, &rules) {
+                break;
+            }
+        }
+        packed.to_state(rules.clone());
+    });
+    println!(
+        "PackedState: {:.0} playouts/s ({:.1} times as many)",
+        packed_rate,
+        packed_rate / state_rate
+    );
+}
@@ -0,0 +1,164 @@
+use amarillo::game::Game;
+use amarillo::game_state::*;
+use amarillo::mcts::*;
+use amarillo::rules::*;
+use amarillo::value_fns::*;
+use rand::SeedableRng;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "selfplay",
+    about = "Plays games between Monte Carlo tree search players and prints the results."
+)]
+struct Opt {
+    /// Number of players (2 to 4).
+    #[structopt(short, long, default_value = "3")]
+    players: u8,
+    /// Number of games to play.
+    #[structopt(short, long, default_value = "1")]
+    games: u32,
+    /// Number of playouts for each move. Defaults to 1000 if no other limit is given. The search
+    /// stops at whichever limit it reaches first.
+    #[structopt(long)]
+    playouts: Option<i32>,
+    /// Number of states each search's tree may hold.
+    #[structopt(long)]
+    nodes: Option<usize>,
+    /// Megabytes each search's tree may take up.
+    #[structopt(long)]
+    memory: Option<usize>,
+    /// Milliseconds to search for on each move. The other limits make the games reproducible,
+    /// but this doesn't.
+    #[structopt(long)]
+    millis: Option<u64>,
+    /// Seed for the random number generator. The same seed gives the same games.
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+    /// JSON file with the rule set to play with. Defaults to the official rules.
+    #[structopt(long)]
+    rules: Option<String>,
+    /// Play the free placement ("grey wall") variant.
+    #[structopt(long)]
+    free_wall: bool,
+    /// Don't search moves that take from a factory display holding the same tiles as another.
+    #[structopt(long)]
+    canonical_moves: bool,
+    /// Number of threads each search runs on.
+    #[structopt(long, default_value = "1")]
+    threads: usize,
+    /// Search a separate tree on each thread and combine them at the end, instead of sharing
+    /// one tree between the threads.
+    #[structopt(long)]
+    root_parallel: bool,
+    /// Keep each player's search tree from one move to the next.
+    #[structopt(long)]
+    reuse_tree: bool,
+    /// How each player chooses its move once the search is over: `max`, `max:<min playouts>`,
+    /// `robust`, `secure` or `secure:<confidence>`. Give it once for all the players or once
+    /// for each, to compare them, in which case the policies change seats from one game to the
+    /// next and their wins are added up.
+    #[structopt(long)]
+    final_move: Vec<FinalMovePolicy>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(err) = check_num_players(opt.players) {
+        eprintln!("{}.", err);
+        std::process::exit(1);
+    }
+    let rules = match RuleSet::from_options(opt.rules.as_deref(), opt.free_wall) {
+        Ok(rules) => rules,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let rules = Arc::new(rules);
+    let mut search_rng = rand::rngs::SmallRng::seed_from_u64(opt.seed);
+    let make_vf: Arc<ValueFunctionFactory> = Arc::new(|| Ok(Box::new(ValueFunctionScore::new())));
+    let max_playouts = match (opt.playouts, opt.nodes, opt.memory, opt.millis) {
+        (None, None, None, None) => Some(1000),
+        _ => opt.playouts,
+    };
+    let config = SearchConfig {
+        time_limit: opt.millis.map(std::time::Duration::from_millis),
+        max_playouts,
+        max_nodes: opt.nodes,
+        max_memory: opt.memory.map(|megabytes| megabytes << 20),
+        num_threads: opt.threads,
+        parallelism: if opt.root_parallel {
+            Parallelism::Root
+        } else {
+            Parallelism::Tree
+        },
+        make_vf: Some(make_vf.clone()),
+        moves: if opt.canonical_moves {
+            MoveGeneration::Canonical
+        } else {
+            MoveGeneration::All
+        },
+        ..SearchConfig::default()
+    };
+    if opt.final_move.len() > 1 && opt.final_move.len() != opt.players as usize {
+        eprintln!("Give one final move policy for all the players or one for each.");
+        std::process::exit(1);
+    }
+    // The configs of the searching players, which move one seat on from one game to the next so
+    // that no final move policy gets the advantage of a seat.
+    let configs: Vec<_> = (0..opt.players as usize)
+        .map(|searcher| SearchConfig {
+            final_move: match opt.final_move.len() {
+                0 => config.final_move,
+                1 => opt.final_move[0],
+                _ => opt.final_move[searcher],
+            },
+            ..config.clone()
+        })
+        .collect();
+    let mut vf = ValueFunctionScore::new();
+    // The searchers, with their threads' value functions, last for all the games.
+    let mut searchers: Vec<_> = configs
+        .iter()
+        .map(|config| Searcher::new(config).unwrap())
+        .collect();
+    let mut wins = vec![0.; opt.players as usize];
+    let mut searcher_wins = vec![0.; opt.players as usize];
+    for game_num in 0..opt.games {
+        let searcher_in_seat = |seat: usize| (seat + game_num as usize) % configs.len();
+        let mut game = Game::new(opt.players, rules.clone(), opt.seed + game_num as u64);
+        for searcher in searchers.iter_mut() {
+            searcher.clear();
+        }
+        let mut retained_playouts = 0;
+        while !game.is_finished() {
+            let state = game.state();
+            let searcher_num = searcher_in_seat(state.player_to_play as usize);
+            let config = &configs[searcher_num];
+            let searcher = &mut searchers[searcher_num];
+            if !opt.reuse_tree {
+                searcher.clear();
+            }
+            let (action, stats) = searcher.search(state, config, &mut vf, &mut search_rng);
+            retained_playouts += stats.retained_playouts;
+            game.play(action).unwrap();
+        }
+        println!("Game {}: scores {:?}", game_num, game.scores());
+        if opt.reuse_tree {
+            println!("Playouts kept between moves: {}", retained_playouts);
+        }
+        for (seat, share) in game.state().player_scores.iter().enumerate() {
+            wins[seat] += share;
+            searcher_wins[searcher_in_seat(seat)] += share;
+        }
+    }
+    println!("Wins: {:?}", wins);
+    if opt.final_move.len() > 1 {
+        println!("Wins by final move policy:");
+        for (policy, policy_wins) in opt.final_move.iter().zip(searcher_wins) {
+            println!("  {:?}: {}", policy, policy_wins);
+        }
+    }
+}
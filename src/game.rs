@@ -0,0 +1,368 @@
+//! A game from start to finish: the state, the refills between rounds and the moves played.
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use std::sync::Arc;
+
+use crate::game_state::*;
+use crate::record::*;
+use crate::rules::RuleSet;
+use crate::scoring::*;
+use crate::zobrist::compute_hash;
+
+/// Something that happened while playing an action.
+#[derive(Clone, PartialEq, Debug)]
+pub enum GameEvent {
+    /// A player played an action.
+    Played { player: u8, action: Action },
+    /// A player's score changed. These come after the action that ended the round.
+    Scored(ScoreEvent),
+    /// A round ended and was scored.
+    RoundEnded { round: u32 },
+    /// The factory displays were refilled for a new round.
+    RoundStarted { round: u32, refill: Refill },
+    /// The game is over, with each player's final score.
+    GameOver { scores: Vec<i32> },
+}
+
+/// Everything needed to take back an action.
+#[derive(Clone, Debug)]
+struct HistoryEntry {
+    action: Action,
+    state: State,
+    rng: SmallRng,
+    round: u32,
+    num_record_entries: usize,
+}
+
+/// Everything needed to play an action again after it has been taken back.
+#[derive(Clone, Debug)]
+struct RedoEntry {
+    history_entry: HistoryEntry,
+    state: State,
+    rng: SmallRng,
+    round: u32,
+    /// The entries of the record from the action on.
+    record_entries: Vec<RecordEntry>,
+    final_scores: Option<Vec<i32>>,
+}
+
+/// Runs a game: plays actions, refills the factory displays at the end of each round and keeps
+/// the history of moves, which can be taken back and played again.
+///
+/// The game owns its random number generator, so the same seed and moves always give the same
+/// refills. Taking back a move also takes back the random numbers drawn after it.
+#[derive(Clone, Debug)]
+pub struct Game {
+    state: State,
+    rng: SmallRng,
+    round: u32,
+    history: Vec<HistoryEntry>,
+    /// The actions taken back, the most recent last.
+    redo_stack: Vec<RedoEntry>,
+    record: GameRecord,
+}
+
+impl Game {
+    /// Starts a new game with the factory displays filled.
+    pub fn new(num_players: u8, rules: Arc<RuleSet>, seed: u64) -> Game {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let state = get_random_initial_state(num_players, rules, &mut rng);
+        let record = GameRecord::new(&state, Some(seed));
+        Game {
+            state,
+            rng,
+            round: 1,
+            history: Vec::new(),
+            redo_stack: Vec::new(),
+            record,
+        }
+    }
+
+    /// Starts a game from a position, which is counted as the first round. The position's hash
+    /// is recomputed, so it needn't have one.
+    pub fn from_state(mut state: State, seed: u64) -> Result<Game, GameError> {
+        validate_state(&state)?;
+        state.hash = compute_hash(&state);
+        let record = GameRecord::new(&state, Some(seed));
+        Ok(Game {
+            state,
+            rng: SmallRng::seed_from_u64(seed),
+            round: 1,
+            history: Vec::new(),
+            redo_stack: Vec::new(),
+            record,
+        })
+    }
+
+    /// Carries on the game in a record. The moves in the record can be taken back.
+    ///
+    /// The refills from here on are drawn with `seed`, so neither it nor the record's seed
+    /// reproduces the whole game, and the new record has no seed.
+    pub fn from_record(record: GameRecord, seed: u64) -> Result<Game, GameError> {
+        record.check()?;
+        let mut game = Game {
+            state: record.initial_state.clone(),
+            rng: SmallRng::seed_from_u64(seed),
+            round: 1,
+            history: Vec::new(),
+            redo_stack: Vec::new(),
+            record: GameRecord::new(&record.initial_state, None),
+        };
+        for entry in record.entries.iter() {
+            match entry {
+                RecordEntry::Action(action) => {
+                    game.history.push(game.history_entry(*action));
+                    game.state = step(game.state.clone(), *action, true).0;
+                    game.record.record_action(*action);
+                }
+                RecordEntry::Refill(refill) => {
+                    refill.apply(&mut game.state);
+                    game.round += 1;
+                    game.record.entries.push(entry.clone());
+                }
+            }
+        }
+        game.record.record_result(&game.state);
+        Ok(game)
+    }
+
+    fn history_entry(&self, action: Action) -> HistoryEntry {
+        HistoryEntry {
+            action,
+            state: self.state.clone(),
+            rng: self.rng.clone(),
+            round: self.round,
+            num_record_entries: self.record.entries.len(),
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The record of the game so far.
+    pub fn record(&self) -> &GameRecord {
+        &self.record
+    }
+
+    /// The round being played, starting from 1.
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished
+    }
+
+    /// The actions played so far, in order.
+    pub fn moves(&self) -> Vec<Action> {
+        self.history.iter().map(|entry| entry.action).collect()
+    }
+
+    pub fn legal_actions(&self) -> Vec<Action> {
+        get_valid_actions(&self.state)
+    }
+
+    /// Each player's score.
+    pub fn scores(&self) -> Vec<i32> {
+        self.state.board_states.iter().map(|b| b.score).collect()
+    }
+
+    /// The players' places, once the game is finished.
+    pub fn ranking(&self) -> Option<Vec<Placing>> {
+        if self.state.is_finished {
+            Some(final_ranking(&self.state))
+        } else {
+            None
+        }
+    }
+
+    /// The player who won, once the game is finished, unless the win is shared.
+    pub fn winner(&self) -> Option<u8> {
+        if !self.state.is_finished {
+            return None;
+        }
+        self.state
+            .player_scores
+            .iter()
+            .position(|share| *share == 1.)
+            .map(|player| player as u8)
+    }
+
+    /// Plays an action for the player to play, refilling the factory displays if it ends the
+    /// round. The actions taken back can't be played again with `redo` afterwards.
+    ///
+    /// Returns what happened, in order. The game is unchanged if the action isn't legal.
+    pub fn play(&mut self, action: Action) -> Result<Vec<GameEvent>, GameError> {
+        let player = self.state.player_to_play;
+        let mut score_events = Vec::new();
+        let (new_state, round_over) = try_step_with_log(&self.state, action, &mut score_events)?;
+        self.redo_stack.clear();
+        self.history.push(self.history_entry(action));
+        self.state = new_state;
+        self.record.record_action(action);
+        let mut events = vec![GameEvent::Played { player, action }];
+        events.extend(score_events.into_iter().map(GameEvent::Scored));
+        if round_over {
+            events.push(GameEvent::RoundEnded { round: self.round });
+            if self.state.is_finished {
+                self.record.record_result(&self.state);
+                events.push(GameEvent::GameOver {
+                    scores: self.scores(),
+                });
+            } else {
+                fill_factory_displays(&mut self.state, &mut self.rng);
+                self.round += 1;
+                let refill = Refill::from_state(&self.state);
+                self.record
+                    .entries
+                    .push(RecordEntry::Refill(refill.clone()));
+                events.push(GameEvent::RoundStarted {
+                    round: self.round,
+                    refill,
+                });
+            }
+        }
+        Ok(events)
+    }
+
+    /// Takes back the last action, and the refill it caused, if any.
+    ///
+    /// Returns the action taken back, or None if no actions have been played.
+    pub fn undo(&mut self) -> Option<Action> {
+        let entry = self.history.pop()?;
+        let action = entry.action;
+        let redo_entry = RedoEntry {
+            history_entry: entry.clone(),
+            state: std::mem::replace(&mut self.state, entry.state),
+            rng: std::mem::replace(&mut self.rng, entry.rng),
+            round: std::mem::replace(&mut self.round, entry.round),
+            record_entries: self.record.entries.split_off(entry.num_record_entries),
+            final_scores: self.record.final_scores.take(),
+        };
+        self.redo_stack.push(redo_entry);
+        Some(action)
+    }
+
+    /// Plays the last action taken back again, restoring the state it led to, including the
+    /// refill it caused.
+    ///
+    /// Returns the action played, or None if there are no actions to play again.
+    pub fn redo(&mut self) -> Option<Action> {
+        let entry = self.redo_stack.pop()?;
+        let action = entry.history_entry.action;
+        self.history.push(entry.history_entry);
+        self.state = entry.state;
+        self.rng = entry.rng;
+        self.round = entry.round;
+        self.record.entries.extend(entry.record_entries);
+        self.record.final_scores = entry.final_scores;
+        Some(action)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.history.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn new_game(seed: u64) -> Game {
+        Game::new(3, Arc::new(RuleSet::default()), seed)
+    }
+
+    fn play_random_action<R: Rng>(game: &mut Game, rng: &mut R) -> Vec<GameEvent> {
+        let legal_actions = game.legal_actions();
+        let action = legal_actions[rng.gen_range(0, legal_actions.len())];
+        game.play(action).unwrap()
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_game() {
+        let mut game = new_game(1);
+        let mut rng = SmallRng::seed_from_u64(2);
+        let mut games = vec![game.clone()];
+        while !game.is_finished() {
+            play_random_action(&mut game, &mut rng);
+            games.push(game.clone());
+        }
+        for previous in games.iter().rev().skip(1) {
+            assert!(game.undo().is_some());
+            assert_eq!(game.state(), previous.state());
+            assert_eq!(game.state().hash, compute_hash(game.state()));
+            assert_eq!(game.round(), previous.round());
+            assert_eq!(game.record(), previous.record());
+        }
+        assert_eq!(game.undo(), None);
+        for next in games.iter().skip(1) {
+            assert!(game.redo().is_some());
+            assert_eq!(game.state(), next.state());
+            assert_eq!(game.state().hash, next.state().hash);
+            assert_eq!(game.round(), next.round());
+            assert_eq!(game.record(), next.record());
+        }
+        assert_eq!(game.redo(), None);
+    }
+
+    #[test]
+    fn playing_after_undo_drops_the_redo_moves() {
+        let mut game = new_game(3);
+        let mut rng = SmallRng::seed_from_u64(4);
+        for _ in 0..3 {
+            play_random_action(&mut game, &mut rng);
+        }
+        let moves = game.moves();
+        game.undo();
+        game.undo();
+        assert!(game.can_redo());
+        let action = *game
+            .legal_actions()
+            .iter()
+            .find(|action| **action != moves[1])
+            .unwrap();
+        game.play(action).unwrap();
+        assert!(!game.can_redo());
+        assert_eq!(game.redo(), None);
+        assert_eq!(game.moves(), vec![moves[0], action]);
+        assert_eq!(
+            game.record().entries,
+            vec![RecordEntry::Action(moves[0]), RecordEntry::Action(action)]
+        );
+    }
+
+    #[test]
+    fn undo_takes_back_the_refill() {
+        let mut game = new_game(5);
+        let mut rng = SmallRng::seed_from_u64(6);
+        loop {
+            let before = game.clone();
+            let events = play_random_action(&mut game, &mut rng);
+            let refilled = events.iter().any(|event| match event {
+                GameEvent::RoundStarted { .. } => true,
+                _ => false,
+            });
+            if !refilled {
+                continue;
+            }
+            let after = game.clone();
+            let action = game.undo().unwrap();
+            assert_eq!(game.state(), before.state());
+            assert_eq!(game.round(), before.round());
+            assert_eq!(game.record(), before.record());
+            // The random numbers are taken back too, so playing the action again refills the
+            // displays the same way.
+            game.play(action).unwrap();
+            assert_eq!(game.state(), after.state());
+            assert_eq!(game.round(), after.round());
+            assert_eq!(game.record(), after.record());
+            break;
+        }
+    }
+}
@@ -1,12 +1,22 @@
+//! The state of a game, the actions that can be played and the rules for playing them.
+use crate::rules::RuleSet;
+use crate::scoring::*;
+use crate::zobrist::*;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::cmp;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::Arc;
 
-const NUM_FACTORY_DISPLAYS: u8 = 7;
+pub const MIN_PLAYERS: u8 = 2;
+pub const MAX_PLAYERS: u8 = 4;
+/// The display number of the centre.
+pub const CENTRE: u8 = std::u8::MAX;
 // blue, yellow, red, green, cyan, first player token
 pub const COLOR_NAMES: [char; 6] = ['B', 'Y', 'R', 'G', 'C', 'F'];
+/// The "color" of the first player token.
+pub const FIRST_PLAYER_TOKEN: u8 = 5;
 #[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct Row {
     pub color: u8,
@@ -16,9 +26,13 @@ pub struct Row {
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct PlayerState {
     pub wall_state: [[bool; 5]; 5],
+    /// The color of each tile on the wall. Zero where there is no tile.
+    pub wall_colors: [[u8; 5]; 5],
     pub rows: [Row; 5],
     pub score: i32,
-    pub floor_tiles: [u8; 6],
+    /// The tiles on the floor line, in the order they were placed. `FIRST_PLAYER_TOKEN` for the
+    /// first player token.
+    pub floor: Vec<u8>,
 }
 #[cfg(unix)]
 fn colorise_adv(string: &str, color_char: char) -> String {
@@ -68,8 +82,35 @@ fn stripped_len(string: &str) -> usize {
     let stripped_bytes = strip_ansi_escapes::strip(&line_bytes).expect("");
     stripped_bytes.len()
 }
-impl Display for PlayerState {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+/// The column of the fixed wall pattern where tiles of the given color go in the given row.
+pub fn fixed_wall_column(row_id: u8, color: u8) -> u8 {
+    (row_id + color) % 5
+}
+
+impl PlayerState {
+    /// Whether the wall already has a tile of the given color in the given row.
+    pub fn row_has_color(&self, row_id: u8, color: u8) -> bool {
+        let row_id = row_id as usize;
+        (0..5).any(|col_id| {
+            self.wall_state[row_id][col_id] && self.wall_colors[row_id][col_id] == color
+        })
+    }
+
+    /// The columns where a tile of the given color may be placed in the given row of a free wall.
+    pub fn free_wall_columns(&self, row_id: u8, color: u8) -> [bool; 5] {
+        let mut columns = [false; 5];
+        for (col_id, allowed) in columns.iter_mut().enumerate() {
+            *allowed = !self.wall_state[row_id as usize][col_id]
+                && (0..5).all(|other_row_id| {
+                    !self.wall_state[other_row_id][col_id]
+                        || self.wall_colors[other_row_id][col_id] != color
+                });
+        }
+        columns
+    }
+
+    /// Formats the board. Empty spaces on a fixed wall are colored by the tile that belongs there.
+    pub fn to_string_with_wall(&self, free_wall: bool) -> String {
         let mut str = String::new();
         for (row_id, row) in self.rows.iter().enumerate() {
             let mut row_str = String::new();
@@ -90,32 +131,68 @@ impl Display for PlayerState {
             }
             let mut wall_row_str = String::new();
             for (j, c) in wall_row.iter().enumerate() {
-                let idx: i32 = ((j as i32) - (row_id as i32) + 5) % 5;
-                let colname = COLOR_NAMES[idx as usize];
                 if *c {
-                    wall_row_str += &colorise(colname);
+                    wall_row_str += &colorise(COLOR_NAMES[self.wall_colors[row_id][j] as usize]);
+                } else if free_wall {
+                    wall_row_str += &colorise_adv(&".".to_string(), 'W');
                 } else {
-                    wall_row_str += &colorise_adv(&".".to_string(), colname);
+                    let idx: i32 = ((j as i32) - (row_id as i32) + 5) % 5;
+                    wall_row_str += &colorise_adv(&".".to_string(), COLOR_NAMES[idx as usize]);
                 }
             }
             let combined_str = format!("{} {}", row_str, wall_row_str);
             str.push_str(&combined_str);
             str.push_str("\n");
         }
-        let mut floor_str = color_arr(self.floor_tiles.iter(), false);
+        let mut floor_str: String = self
+            .floor
+            .iter()
+            .map(|tile| colorise(COLOR_NAMES[*tile as usize]))
+            .collect();
         let score_str = format!("{}", self.score);
         let width = stripped_len(&floor_str) + score_str.len();
-        for _ in 0..(11 - width) {
+        for _ in 0..11_usize.saturating_sub(width) {
             floor_str.push(' ');
         }
         floor_str.push_str(&score_str);
         str.push_str(&floor_str);
-        write!(f, "{}", str)
+        str
+    }
+}
+
+impl Display for PlayerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_with_wall(false))
     }
 }
+/// The number of factory displays used in a game with the given number of players.
+pub const fn num_factory_displays(num_players: u8) -> u8 {
+    2 * num_players + 1
+}
+
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct CentralState {
-    pub central_state_arr: [[u8; 6]; 8],
+    /// Tiles on each factory display, followed by the tiles in the centre.
+    pub central_state_arr: Vec<[u8; 6]>,
+}
+impl CentralState {
+    pub fn new(num_factory_displays: u8) -> CentralState {
+        CentralState {
+            central_state_arr: vec![[0; 6]; num_factory_displays as usize + 1],
+        }
+    }
+    /// The number of factory displays. This is also the index of the centre.
+    pub fn num_factory_displays(&self) -> u8 {
+        (self.central_state_arr.len() - 1) as u8
+    }
+    /// The index in `central_state_arr` of a factory display or the centre.
+    pub fn display_index(&self, display_number: u8) -> usize {
+        if display_number == CENTRE {
+            self.num_factory_displays() as usize
+        } else {
+            display_number as usize
+        }
+    }
 }
 fn arr_to_str(row: [u8; 6]) -> String {
     let mut output = String::new();
@@ -129,28 +206,31 @@ fn arr_to_str(row: [u8; 6]) -> String {
 
 impl Display for CentralState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut num_tiles = [0; 1 + NUM_FACTORY_DISPLAYS as usize];
+        let num_factory_displays = self.num_factory_displays();
+        let mut num_tiles = vec![0; 1 + num_factory_displays as usize];
         for (display_number, central_state_row) in self.central_state_arr.iter().enumerate() {
-            num_tiles[display_number] = central_state_row.iter().sum::<u8>();
+            num_tiles[display_number] = central_state_row
+                .iter()
+                .map(|count| u32::from(*count))
+                .sum::<u32>();
         }
         let mut output = String::new();
-        for i in 0..=NUM_FACTORY_DISPLAYS {
+        for i in 0..=num_factory_displays {
             if num_tiles[i as usize] > 0 {
-                let dump_str = if i == NUM_FACTORY_DISPLAYS {
-                    ": the centre"
+                if i == num_factory_displays {
+                    output.push_str("C: the centre");
                 } else {
-                    ""
-                };
-                output.push_str(&format!("{}{}    ", i, dump_str));
+                    output.push_str(&format!("{}    ", i));
+                }
             }
         }
         output.push_str("\n");
-        for factory_display in 0..=NUM_FACTORY_DISPLAYS {
+        for factory_display in 0..=num_factory_displays {
             if num_tiles[factory_display as usize] > 0 {
                 let central_state_row = self.central_state_arr[factory_display as usize];
                 output.push_str(&arr_to_str(central_state_row));
-                if factory_display < NUM_FACTORY_DISPLAYS {
-                    for _ in 0..(5 - num_tiles[factory_display as usize]) {
+                if factory_display < num_factory_displays {
+                    for _ in 0..5_u32.saturating_sub(num_tiles[factory_display as usize]) {
                         output.push_str(" ");
                     }
                 }
@@ -161,22 +241,87 @@ impl Display for CentralState {
 }
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub struct State {
-    pub board_states: [PlayerState; 3],
+    pub board_states: Vec<PlayerState>,
     pub central_state: CentralState,
     pub player_to_play: u8,
     pub bag: [u8; 5],
     pub lid: [u8; 5],
     pub is_finished: bool,
-    pub player_scores: [f32; 3],
+    pub player_scores: Vec<f32>,
+    pub rules: Arc<RuleSet>,
+    /// Whether the round is over and players are choosing where their complete rows go on the
+    /// wall. Only happens in the free wall variant.
+    pub tiling: bool,
+    /// The Zobrist hash of the state (see the `zobrist` module), kept up to date by `step` and
+    /// `fill_factory_displays`. Anything else that changes the state must recompute it.
+    #[serde(default)]
+    pub hash: u64,
+}
+impl State {
+    pub fn num_players(&self) -> u8 {
+        self.board_states.len() as u8
+    }
+    /// The number of factory displays. This is also the index of the centre.
+    pub fn num_factory_displays(&self) -> u8 {
+        self.central_state.num_factory_displays()
+    }
+}
+
+/// Why an action or a state was rejected.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum GameError {
+    /// The action can't be played in the state.
+    IllegalAction(Action),
+    /// The game is over, so no more actions can be played.
+    GameFinished,
+    /// The state doesn't have the right number of tiles of a color (or first player tokens).
+    TileCount {
+        color: u8,
+        expected: u32,
+        found: u32,
+    },
+    /// The state breaks the rules in some other way, e.g. a pattern line has too many tiles.
+    InvalidState(String),
+    /// A value function couldn't load its model.
+    ModelLoad(String),
+    /// The search can't run with the config it was given.
+    InvalidConfig(String),
+}
+
+impl Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GameError::IllegalAction(action) => write!(f, "{} is not a legal move", action),
+            GameError::GameFinished => write!(f, "the game is finished"),
+            GameError::TileCount {
+                color,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected {} {} tiles, state has {}",
+                expected, COLOR_NAMES[*color as usize], found
+            ),
+            GameError::InvalidState(message) => write!(f, "{}", message),
+            GameError::ModelLoad(message) => write!(f, "could not load the model: {}", message),
+            GameError::InvalidConfig(message) => write!(f, "invalid search config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for GameError {}
+
+fn invalid_state<T>(message: String) -> Result<T, GameError> {
+    Err(GameError::InvalidState(message))
 }
 
 fn random_choice<R: Rng>(int_weights: &[u8], rng: &mut R) -> usize {
     // Choose an element randomly according to the given weights.
-    let sum: u8 = int_weights.iter().sum();
+    let sum: u32 = int_weights.iter().map(|weight| u32::from(*weight)).sum();
     let p: f64 = rng.gen();
     let mut weight_sum = 0;
     for (i, weight) in int_weights.iter().enumerate() {
-        weight_sum += weight;
+        weight_sum += u32::from(*weight);
         if (weight_sum as f64) / (sum as f64) > p {
             return i;
         }
@@ -184,89 +329,226 @@ fn random_choice<R: Rng>(int_weights: &[u8], rng: &mut R) -> usize {
     int_weights.len() - 1
 }
 
-fn is_first_move(state: &State) -> bool {
-    for board in state.board_states.iter() {
-        for row in board.rows.iter() {
-            if row.count > 0 {
-                return false;
-            }
-        }
-        for i in 0..5 {
-            if board.floor_tiles[i] > 0 {
-                return false;
-            }
-        }
-    }
-    true
+/// The number of tiles of each color (and first player tokens) that a state should have.
+pub fn expected_tile_counts(rules: &RuleSet) -> [u8; 6] {
+    let num_tiles = rules.tiles_per_color;
+    [num_tiles, num_tiles, num_tiles, num_tiles, num_tiles, 1]
 }
 
-#[cfg(debug_assertions)]
-pub fn check_counts(state: &State) {
-    // Checks that no tiles have been gained or lost.
-    let mut tiles: [u8; 6] = [0; 6];
-    let initial_tiles = [NUM_TILES, NUM_TILES, NUM_TILES, NUM_TILES, NUM_TILES, 1];
+/// Counts the tiles of each color (and first player tokens) in the state, wherever they are.
+///
+/// The counts are summed in `u32`, so that a state from outside the engine with too many tiles
+/// can't overflow them.
+pub fn count_tiles(state: &State) -> [u32; 6] {
+    let mut tiles: [u32; 6] = [0; 6];
     for color in 0..6 {
         // Add from factories and dump.
-        for factory in 0..=NUM_FACTORY_DISPLAYS {
-            tiles[color] += state.central_state.central_state_arr[factory as usize][color];
+        for factory in state.central_state.central_state_arr.iter() {
+            tiles[color] += u32::from(factory[color]);
         }
 
         for board in state.board_states.iter() {
-            tiles[color] += board.floor_tiles[color];
+            tiles[color] += board.floor.iter().filter(|t| **t == color as u8).count() as u32;
         }
 
-        if color == 6 {
+        if color == 5 {
             continue;
         }
         for board in state.board_states.iter() {
             for row in board.rows.iter() {
                 if row.color == (color as u8) {
-                    tiles[color] += row.count;
+                    tiles[color] += u32::from(row.count);
                 }
             }
-            if color < 5 {
-                for row_id in 0..5 {
-                    let col_id = (row_id + color) % 5;
-                    if board.wall_state[row_id][col_id] {
+            for row_id in 0..5 {
+                for col_id in 0..5 {
+                    if board.wall_state[row_id][col_id]
+                        && board.wall_colors[row_id][col_id] == color as u8
+                    {
                         tiles[color] += 1;
                     }
                 }
             }
         }
-        if color < 5 {
-            tiles[color] += state.bag[color];
-            tiles[color] += state.lid[color];
+        tiles[color] += u32::from(state.bag[color]);
+        tiles[color] += u32::from(state.lid[color]);
+    }
+    tiles
+}
+
+/// Checks that no tiles have been gained or lost.
+pub fn check_tile_counts(state: &State) -> Result<(), GameError> {
+    let tiles = count_tiles(state);
+    let expected_tiles = expected_tile_counts(&state.rules);
+    for color in 0..6 {
+        if tiles[color] != u32::from(expected_tiles[color]) {
+            return Err(GameError::TileCount {
+                color: color as u8,
+                expected: u32::from(expected_tiles[color]),
+                found: tiles[color],
+            });
         }
-        if tiles[color] != initial_tiles[color] {
-            panic!(
-                "The following state is invalid:\n{}\nExpected {} {} tiles, state has {}.",
-                state.to_string(),
-                initial_tiles[color],
-                COLOR_NAMES[color],
-                tiles[color]
-            );
+    }
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+pub fn check_counts(state: &State) {
+    if let Err(err) = check_tile_counts(state) {
+        panic!(
+            "The following state is invalid:\n{}\n{}.",
+            state.to_string(),
+            err
+        );
+    }
+}
+
+/// Checks the rules that apply to each board on its own.
+fn check_board(board: &PlayerState, rules: &RuleSet) -> Result<(), GameError> {
+    for row_id in 0..5 {
+        for col_id in 0..5 {
+            if !board.wall_state[row_id][col_id] {
+                continue;
+            }
+            let color = board.wall_colors[row_id][col_id];
+            if color >= 5 {
+                return invalid_state(format!("there is no color {} on the wall", color));
+            }
+            if !rules.free_wall && fixed_wall_column(row_id as u8, color) != col_id as u8 {
+                return invalid_state(format!(
+                    "{} can't be in column {} of row {} of the wall",
+                    COLOR_NAMES[color as usize], col_id, row_id
+                ));
+            }
+            for other_id in 0..5 {
+                let repeated_in_row = other_id != col_id
+                    && board.wall_state[row_id][other_id]
+                    && board.wall_colors[row_id][other_id] == color;
+                let repeated_in_col = other_id != row_id
+                    && board.wall_state[other_id][col_id]
+                    && board.wall_colors[other_id][col_id] == color;
+                if repeated_in_row || repeated_in_col {
+                    return invalid_state(format!(
+                        "{} is on the wall twice in the same row or column",
+                        COLOR_NAMES[color as usize]
+                    ));
+                }
+            }
         }
     }
+    let has_token = board.floor.contains(&FIRST_PLAYER_TOKEN);
+    if board.floor.len() > cmp::max(rules.floor_capacity(), has_token as usize) {
+        return invalid_state(format!(
+            "the floor line has {} tiles, but only holds {}",
+            board.floor.len(),
+            rules.floor_capacity()
+        ));
+    }
+    if board.floor.iter().any(|tile| *tile > FIRST_PLAYER_TOKEN) {
+        return invalid_state("there is no such tile on the floor line".to_string());
+    }
+    for (row_id, row) in board.rows.iter().enumerate() {
+        if row.count > row_id as u8 + 1 {
+            return invalid_state(format!("pattern line {} has {} tiles", row_id, row.count));
+        }
+        if row.count > 0 && row.color >= 5 {
+            return invalid_state(format!(
+                "there is no color {} on pattern line {}",
+                row.color, row_id
+            ));
+        }
+        if row.count > 0 && board.row_has_color(row_id as u8, row.color) {
+            return invalid_state(format!(
+                "pattern line {} holds {}, which is already on the wall",
+                row_id, COLOR_NAMES[row.color as usize]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a game can have `num_players` players, returning what's wrong if not.
+pub fn check_num_players(num_players: u8) -> Result<(), String> {
+    if num_players < MIN_PLAYERS || num_players > MAX_PLAYERS {
+        return Err(format!(
+            "The number of players must be between {} and {}",
+            MIN_PLAYERS, MAX_PLAYERS
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that a state from outside the engine, e.g. one sent to a server, is consistent with
+/// the rules, so that it can be played from.
+///
+/// The hash isn't checked, since it's derived from the rest of the state and a state written by
+/// hand won't have one. Recompute it with `compute_hash` before playing from the state.
+pub fn validate_state(state: &State) -> Result<(), GameError> {
+    let num_players = state.num_players();
+    if num_players < MIN_PLAYERS || num_players > MAX_PLAYERS {
+        return invalid_state(format!("{} players", num_players));
+    }
+    if state.player_scores.len() != num_players as usize {
+        return invalid_state(format!(
+            "{} player scores for {} players",
+            state.player_scores.len(),
+            num_players
+        ));
+    }
+    if state.central_state.central_state_arr.len() != num_factory_displays(num_players) as usize + 1
+    {
+        return invalid_state(format!(
+            "{} factory displays, but {} players need {}",
+            state
+                .central_state
+                .central_state_arr
+                .len()
+                .saturating_sub(1),
+            num_players,
+            num_factory_displays(num_players)
+        ));
+    }
+    state
+        .rules
+        .validate()
+        .map_err(|message| GameError::InvalidState(format!("invalid rules: {}", message)))?;
+    if state.player_to_play >= num_players {
+        return invalid_state(format!("there is no player {}", state.player_to_play));
+    }
+    if state.tiling && !state.rules.free_wall {
+        return invalid_state("only a free wall is tiled by the players".to_string());
+    }
+    for board in state.board_states.iter() {
+        check_board(board, &state.rules)?;
+    }
+    check_tile_counts(state)?;
+    if !state.is_finished && !has_legal_action(state) {
+        return invalid_state("the game isn't over, but no move can be played".to_string());
+    }
+    Ok(())
 }
 
 impl Display for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut board_strs: [String; 4] = Default::default();
-        for (i, board_state) in self.board_states.iter().enumerate() {
-            board_strs[i] = board_state.to_string();
-        }
+        let mut board_strs: Vec<String> = self
+            .board_states
+            .iter()
+            .map(|board_state| board_state.to_string_with_wall(self.rules.free_wall))
+            .collect();
         let to_play_str = if self.is_finished {
             "".to_string()
+        } else if self.tiling {
+            format!("Player {} to tile. ", self.player_to_play)
         } else {
             format!("Player {} to play. ", self.player_to_play)
         };
-        board_strs[3] = format!(
+        board_strs.push(format!(
             "{}\n{}\nbag {}\nlid {}",
             to_play_str,
             self.central_state.to_string(),
             color_arr(self.bag.iter(), false),
             color_arr(self.lid.iter(), false)
-        );
+        ));
         let mut output_lines = Vec::new();
         for board_str in board_strs.iter() {
             let lines = board_str.split('\n');
@@ -290,76 +572,235 @@ impl Display for State {
     }
 }
 
+/// An action. See the `notation` module for how actions are written.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-pub struct Action {
-    pub display_number: u8,
-    pub color: u8,
-    pub row_id: u8,
+pub enum Action {
+    /// Take all the tiles of a color from a factory display (or the centre, `CENTRE`) and put them
+    /// on a row (or the floor, row 5).
+    Take {
+        display_number: u8,
+        color: u8,
+        row_id: u8,
+    },
+    /// Move the tile from a complete row to a column of the wall. Only in the free wall variant.
+    Place { row_id: u8, col_id: u8 },
 }
 
-impl Display for Action {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            format!(
-                "Move {} tiles from display {} to row {}.",
-                colorise(COLOR_NAMES[self.color as usize]),
-                self.display_number,
-                self.row_id
-            )
-        )
+/// The first complete row of the player to play, which is the next to be moved to the wall.
+fn next_row_to_tile(state: &State) -> Option<u8> {
+    let board = &state.board_states[state.player_to_play as usize];
+    (0..5).find(|row_id| board.rows[*row_id as usize].count == row_id + 1)
+}
+
+/// The most actions there can be in a state: taking any of the five colors from any factory
+/// display or the centre to any row or the floor.
+pub const MAX_ACTIONS: usize = (num_factory_displays(MAX_PLAYERS) as usize + 1) * 5 * 6;
+
+/// A list of actions with room for `MAX_ACTIONS`, which `fill_valid_actions` fills without
+/// allocating. It dereferences to a slice of the actions.
+pub struct ActionBuffer {
+    actions: [Action; MAX_ACTIONS],
+    len: usize,
+}
+
+impl ActionBuffer {
+    pub fn new() -> ActionBuffer {
+        ActionBuffer {
+            actions: [Action::Place {
+                row_id: 0,
+                col_id: 0,
+            }; MAX_ACTIONS],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Adds an action. Panics if the buffer is full.
+    pub fn push(&mut self, action: Action) {
+        self.actions[self.len] = action;
+        self.len += 1;
     }
 }
 
-/// Gets the actions that can be played in the given state.
-pub fn get_valid_actions(state: &State) -> Vec<Action> {
-    let mut valid_actions = Vec::new();
-    let board = &state.board_states[state.player_to_play as usize];
-    let central_state = &state.central_state;
+impl Default for ActionBuffer {
+    fn default() -> ActionBuffer {
+        ActionBuffer::new()
+    }
+}
+
+impl std::ops::Deref for ActionBuffer {
+    type Target = [Action];
+
+    fn deref(&self) -> &[Action] {
+        &self.actions[..self.len]
+    }
+}
 
-    let mut dests = [[false; 6]; 5];
-    for dest in dests.iter_mut() {
-        dest[5] = true;
+impl fmt::Debug for ActionBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
     }
+}
+
+/// The rows where a player may put the tiles of each color, as masks with bit `row_id`. The
+/// floor, row 5, is always allowed.
+fn take_destinations(board: &PlayerState) -> [u8; 5] {
+    let mut dests = [1 << 5; 5];
     for row_id in 0..5 {
-        let row = board.rows[row_id];
+        let row = board.rows[row_id as usize];
         if row.count > 0 {
-            if row.count < row_id as u8 + 1 {
+            if row.count < row_id + 1 {
                 // More space to add to the row.
-                dests[row.color as usize][row_id] = true;
+                dests[row.color as usize] |= 1 << row_id;
             }
         } else {
             // Row is empty.
             for (color, dest) in dests.iter_mut().enumerate() {
-                let color_on_wall = board.wall_state[row_id][(row_id + color) % 5];
-                if !color_on_wall {
-                    dest[row_id] = true;
+                if !board.row_has_color(row_id, color as u8) {
+                    *dest |= 1 << row_id;
                 }
             }
         }
     }
-    for display_number in 0..=NUM_FACTORY_DISPLAYS {
+    dests
+}
+
+/// Which of the actions that can be played move generation gives.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MoveGeneration {
+    /// All of them.
+    All,
+    /// All but those taking from a factory display that holds the same tiles as an earlier one.
+    /// These lead to the same positions as taking from the earlier display, apart from the
+    /// numbering of the displays, so a search needn't try both. (No two rows, or a row and the
+    /// floor, are equivalent destinations, as a row always gets at least one of the tiles.)
+    Canonical,
+}
+
+/// Whether a factory display holds the same tiles as an earlier one. The centre never does.
+fn is_duplicate_display(state: &State, display_index: usize) -> bool {
+    let arr = &state.central_state.central_state_arr;
+    display_index < state.num_factory_displays() as usize
+        && arr[..display_index].contains(&arr[display_index])
+}
+
+/// Calls `f` with each action that can be played in the given state, in the order
+/// `get_valid_actions` gives them.
+fn for_each_valid_action<F: FnMut(Action)>(state: &State, f: F) {
+    for_each_action(state, MoveGeneration::All, f);
+}
+
+/// Calls `f` with each action `generation` gives in the given state, in the order
+/// `get_valid_actions` gives them.
+fn for_each_action<F: FnMut(Action)>(state: &State, generation: MoveGeneration, mut f: F) {
+    let board = &state.board_states[state.player_to_play as usize];
+
+    if state.tiling {
+        if let Some(row_id) = next_row_to_tile(&state) {
+            let color = board.rows[row_id as usize].color;
+            for (col_id, allowed) in board.free_wall_columns(row_id, color).iter().enumerate() {
+                if *allowed {
+                    f(Action::Place {
+                        row_id,
+                        col_id: col_id as u8,
+                    });
+                }
+            }
+        }
+        return;
+    }
+
+    let dests = take_destinations(board);
+    for (display_index, tiles) in state.central_state.central_state_arr.iter().enumerate() {
+        if generation == MoveGeneration::Canonical && is_duplicate_display(state, display_index) {
+            continue;
+        }
+        let display_number = if display_index == state.num_factory_displays() as usize {
+            CENTRE
+        } else {
+            display_index as u8
+        };
         for (color, dest) in dests.iter().enumerate() {
-            if central_state.central_state_arr[display_number as usize][color] == 0 {
+            if tiles[color] == 0 {
                 continue;
             }
-            for (row_id, allowed) in dest.iter().enumerate() {
-                if !*allowed {
-                    continue;
+            for row_id in 0..6 {
+                if dest & (1 << row_id) != 0 {
+                    f(Action::Take {
+                        display_number,
+                        color: color as u8,
+                        row_id,
+                    });
                 }
-                valid_actions.push(Action {
-                    display_number: display_number as u8,
-                    color: color as u8,
-                    row_id: row_id as u8,
-                })
             }
         }
     }
+}
+
+/// Gets the actions that can be played in the given state.
+pub fn get_valid_actions(state: &State) -> Vec<Action> {
+    let mut valid_actions = Vec::with_capacity(count_legal_actions(state));
+    for_each_valid_action(state, |action| valid_actions.push(action));
     valid_actions
 }
 
-fn score_tile_placement(wall_state: &[[bool; 5]; 5], row_id: u8, col_id: u8) -> i32 {
+/// Gets the actions `generation` gives in the given state.
+pub fn get_actions(state: &State, generation: MoveGeneration) -> Vec<Action> {
+    let mut actions = Vec::with_capacity(count_legal_actions(state));
+    for_each_action(state, generation, |action| actions.push(action));
+    actions
+}
+
+/// Puts the actions that can be played in the given state in `buffer`, replacing its contents,
+/// in the order `get_valid_actions` gives them. Unlike `get_valid_actions`, this doesn't
+/// allocate.
+pub fn fill_valid_actions(state: &State, buffer: &mut ActionBuffer) {
+    buffer.clear();
+    for_each_valid_action(state, |action| buffer.push(action));
+}
+
+/// The number of actions that can be played in the given state, without listing them.
+pub fn count_legal_actions(state: &State) -> usize {
+    let board = &state.board_states[state.player_to_play as usize];
+    if state.tiling {
+        return next_row_to_tile(state).map_or(0, |row_id| {
+            let color = board.rows[row_id as usize].color;
+            board
+                .free_wall_columns(row_id, color)
+                .iter()
+                .filter(|allowed| **allowed)
+                .count()
+        });
+    }
+    let dests = take_destinations(board);
+    state
+        .central_state
+        .central_state_arr
+        .iter()
+        .map(|tiles| {
+            (0..5)
+                .filter(|color| tiles[*color] > 0)
+                .map(|color| dests[color].count_ones() as usize)
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+/// Whether any action can be played in the given state.
+pub fn has_legal_action(state: &State) -> bool {
+    if state.tiling {
+        count_legal_actions(state) > 0
+    } else {
+        // Tiles can always go on the floor.
+        has_tiles_left(state)
+    }
+}
+
+/// The lengths of the horizontal and vertical lines of adjacent tiles on the wall through a tile.
+fn tile_chains(wall_state: &[[bool; 5]; 5], row_id: u8, col_id: u8) -> (u8, u8) {
     let mut pos = col_id as i8;
     while (pos > 0) && wall_state[row_id as usize][(pos - 1) as usize] {
         pos -= 1;
@@ -388,80 +829,185 @@ fn score_tile_placement(wall_state: &[[bool; 5]; 5], row_id: u8, col_id: u8) ->
     let num_below = pos - row_id as i8;
     assert!(num_below >= 0);
 
-    let horizontal_score = num_left + num_right + 1;
-    let vertical_score = num_above + num_below + 1;
-    if cmp::min(horizontal_score, vertical_score) == 1 {
-        cmp::max(horizontal_score, vertical_score) as i32
+    (
+        (num_left + num_right + 1) as u8,
+        (num_above + num_below + 1) as u8,
+    )
+}
+
+/// The points for a tile placed on the wall, given the lines of adjacent tiles through it.
+fn score_tile_placement(horizontal: u8, vertical: u8) -> i32 {
+    if cmp::min(horizontal, vertical) == 1 {
+        cmp::max(horizontal, vertical) as i32
     } else {
-        (horizontal_score + vertical_score) as i32
+        (horizontal + vertical) as i32
     }
 }
 
-fn score_and_move_floor_tiles(board: &mut PlayerState, lid: &mut [u8; 5]) {
-    let initial_sum = board.floor_tiles.iter().sum::<u8>() + lid.iter().sum::<u8>();
-    let mut num_so_far = 0;
+fn score_and_move_floor_tiles<L: ScoreLog + ?Sized>(
+    player: u8,
+    board: &mut PlayerState,
+    lid: &mut [u8; 5],
+    floor_penalties: &[i32],
+    log: &mut L,
+) {
     let mut penalty = 0;
-    for (color, value) in board.floor_tiles.iter_mut().enumerate() {
-        for _ in 0..*value {
-            if num_so_far <= 1 {
-                penalty += 1;
-            } else if num_so_far <= 4 {
-                penalty += 2;
-            } else if num_so_far <= 6 {
-                penalty += 3;
-            }
-            num_so_far += 1;
-        }
-        if (color != 5) && (*value > 0) {
-            lid[color] += *value;
-            *value = 0;
+    for (slot, tile) in board.floor.iter().enumerate() {
+        penalty += floor_penalties.get(slot).cloned().unwrap_or(0);
+        if *tile != FIRST_PLAYER_TOKEN {
+            lid[*tile as usize] += 1;
         }
     }
+    let initial_score = board.score;
     board.score -= penalty;
     board.score = std::cmp::max(board.score, 0);
-    let final_sum = board.floor_tiles.iter().sum::<u8>() + lid.iter().sum::<u8>();
-    if initial_sum != final_sum {
-        panic!("{} {}", initial_sum, final_sum);
+    if !board.floor.is_empty() && log.wants_events() {
+        log.log(ScoreEvent::FloorPenalty {
+            player,
+            tiles: board.floor.clone(),
+            penalty,
+            points: board.score - initial_score,
+        });
+    }
+    // The first player token stays with its holder. Retaining keeps the floor's capacity for the
+    // next round.
+    board.floor.retain(|tile| *tile == FIRST_PLAYER_TOKEN);
+}
+
+/// Puts tiles on the floor line. The ones that don't fit go to the lid.
+fn put_on_floor(board: &mut PlayerState, lid: &mut [u8; 5], color: u8, count: u8, capacity: usize) {
+    let num_fitting = cmp::min(count as usize, capacity.saturating_sub(board.floor.len()));
+    for _ in 0..num_fitting {
+        board.floor.push(color);
     }
+    lid[color as usize] += count - num_fitting as u8;
+}
+
+/// Puts the first player token on the floor line. If the line is full, the last tile on it goes
+/// to the lid to make room.
+fn put_token_on_floor(board: &mut PlayerState, lid: &mut [u8; 5], capacity: usize) {
+    if board.floor.len() >= capacity {
+        if let Some(tile) = board.floor.pop() {
+            lid[tile as usize] += 1;
+        }
+    }
+    board.floor.push(FIRST_PLAYER_TOKEN);
+}
+
+/// Moves all but one tile from a complete row to the lid and the other tile to the wall.
+fn move_row_to_wall<L: ScoreLog + ?Sized>(
+    player: u8,
+    board: &mut PlayerState,
+    lid: &mut [u8; 5],
+    row_id: u8,
+    col_id: u8,
+    log: &mut L,
+) {
+    let row = &mut board.rows[row_id as usize];
+    let color = row.color;
+    lid[color as usize] += row.count - 1;
+    board.wall_state[row_id as usize][col_id as usize] = true;
+    board.wall_colors[row_id as usize][col_id as usize] = color;
+    *row = Row { color: 0, count: 0 };
+    let (horizontal, vertical) = tile_chains(&board.wall_state, row_id, col_id);
+    let points = score_tile_placement(horizontal, vertical);
+    board.score += points;
+    log.log(ScoreEvent::TilePlaced {
+        player,
+        row_id,
+        col_id,
+        color,
+        horizontal,
+        vertical,
+        points,
+    });
 }
 
 /// Score and reset.
 ///
-/// Returns bool, indicating whether the game is finished.
-fn score_and_reset(state: &mut State) {
+/// On a fixed wall, complete rows are moved to the wall here. On a free wall, they have already
+/// been moved by the players during tiling.
+fn score_and_reset<L: ScoreLog + ?Sized>(state: &mut State, log: &mut L) {
     #[cfg(debug_assertions)]
     check_counts(&state);
 
     // Update the player to play.
-    for player_id in 0..3 {
-        if state.board_states[player_id as usize].floor_tiles[5] > 0 {
+    for player_id in 0..state.num_players() {
+        if state.board_states[player_id as usize]
+            .floor
+            .contains(&FIRST_PLAYER_TOKEN)
+        {
             state.player_to_play = player_id;
         }
     }
 
     // Empty full rows and score. Subtract points for the floor tiles. Move the floor tiles to the lid.
-    for (_player_id, board) in state.board_states.iter_mut().enumerate() {
+    for (player_id, board) in state.board_states.iter_mut().enumerate() {
         for row_id in 0..5 {
-            let mut row = &mut board.rows[row_id];
-            assert!(row.count <= row_id as u8 + 1);
-            if row.count < row_id as u8 + 1 {
+            let row = board.rows[row_id as usize];
+            assert!(row.count <= row_id + 1);
+            if row.count < row_id + 1 {
                 continue;
             }
-            let col_id: u8 = (row_id as u8 + row.color) % 5;
-
-            // Move all but one tile from the row to the lid and the other tile to the wall.
-            state.lid[row.color as usize] += row.count - 1;
-            board.wall_state[row_id as usize][col_id as usize] = true;
-            row.count = 0;
-            board.score += score_tile_placement(&board.wall_state, row_id as u8, col_id);
+            assert!(!state.rules.free_wall);
+            move_row_to_wall(
+                player_id as u8,
+                board,
+                &mut state.lid,
+                row_id,
+                fixed_wall_column(row_id, row.color),
+                log,
+            );
         }
 
-        score_and_move_floor_tiles(board, &mut state.lid);
+        score_and_move_floor_tiles(
+            player_id as u8,
+            board,
+            &mut state.lid,
+            &state.rules.floor_penalties,
+            log,
+        );
     }
     #[cfg(debug_assertions)]
     check_counts(&state);
 }
 
+/// Finds the next complete row to be moved to a free wall, in player order and then from the top
+/// row down, and makes its owner the player to play. The tiles of complete rows that can't go
+/// anywhere on the wall drop to the floor.
+///
+/// Returns whether tiling is over, in which case the round has been scored and reset.
+fn advance_tiling<L: ScoreLog + ?Sized>(state: &mut State, log: &mut L) -> bool {
+    for player_id in 0..state.num_players() {
+        let board = &mut state.board_states[player_id as usize];
+        for row_id in 0..5 {
+            let row = board.rows[row_id as usize];
+            if row.count < row_id + 1 {
+                continue;
+            }
+            if board
+                .free_wall_columns(row_id, row.color)
+                .iter()
+                .any(|x| *x)
+            {
+                state.player_to_play = player_id;
+                return false;
+            }
+            put_on_floor(
+                board,
+                &mut state.lid,
+                row.color,
+                row.count,
+                state.rules.floor_capacity(),
+            );
+            board.rows[row_id as usize] = Row { color: 0, count: 0 };
+        }
+    }
+    state.tiling = false;
+    score_and_reset(state, log);
+    true
+}
+
 ///  Determine whether the game is finished.
 ///
 ///  A game of Amarillo is over when:
@@ -469,7 +1015,7 @@ fn score_and_reset(state: &mut State) {
 ///  * a player has a horizontal
 ///  * there are no tiles in the bag or lid to refill with
 fn is_finished(state: &State) -> bool {
-    if !get_valid_actions(&state).is_empty() {
+    if has_legal_action(state) {
         return false;
     }
     for board in state.board_states.iter() {
@@ -485,15 +1031,18 @@ fn is_finished(state: &State) -> bool {
             }
         }
     }
-    let bag_sum = state.bag.iter().sum::<u8>();
-    let lid_sum = state.lid.iter().sum::<u8>();
-    bag_sum + lid_sum == 0
+    state
+        .bag
+        .iter()
+        .chain(state.lid.iter())
+        .all(|count| *count == 0)
 }
 
 /// Score vertical and horizontal rows and sets of colors.
-fn score_bonuses(state: &mut State) {
-    let mut max_score = -1000;
-    for (_player_id, board) in state.board_states.iter_mut().enumerate() {
+fn score_bonuses<L: ScoreLog + ?Sized>(state: &mut State, log: &mut L) {
+    let rules = &state.rules;
+    for (player_id, board) in state.board_states.iter_mut().enumerate() {
+        let player = player_id as u8;
         for row_id in 0..5 {
             let mut is_full_row = true;
             for col_id in 0..5 {
@@ -502,7 +1051,12 @@ fn score_bonuses(state: &mut State) {
                 }
             }
             if is_full_row {
-                board.score += 2;
+                board.score += rules.row_bonus;
+                log.log(ScoreEvent::RowBonus {
+                    player,
+                    row_id: row_id as u8,
+                    points: rules.row_bonus,
+                });
             }
         }
 
@@ -514,74 +1068,179 @@ fn score_bonuses(state: &mut State) {
                 }
             }
             if is_full_col {
-                board.score += 7;
+                board.score += rules.column_bonus;
+                log.log(ScoreEvent::ColumnBonus {
+                    player,
+                    col_id: col_id as u8,
+                    points: rules.column_bonus,
+                });
             }
         }
         for color in 0..5 {
-            let mut color_score = 10;
-            for i in 0..5 {
-                if !board.wall_state[i][(i + color) % 5] {
+            let mut color_score = rules.color_bonus;
+            for row_id in 0..5 {
+                if !board.row_has_color(row_id, color) {
                     color_score = 0;
                 }
             }
             board.score += color_score;
-            if color_score > 0 {}
+            if color_score > 0 {
+                log.log(ScoreEvent::ColorBonus {
+                    player,
+                    color,
+                    points: color_score,
+                });
+            }
         }
-        max_score = cmp::max(max_score, board.score);
     }
+    set_player_scores(state);
+}
+
+/// A player's place at the end of the game.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Placing {
+    /// 1 for the winner. Players tied on score and complete rows share a place.
+    pub place: u8,
+    pub player: u8,
+    pub score: i32,
+    /// The number of complete horizontal rows on the player's wall, which breaks ties on score.
+    pub complete_rows: u8,
+}
+
+impl Display for Placing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self.place {
+            1 => "st",
+            2 => "nd",
+            3 => "rd",
+            _ => "th",
+        };
+        write!(
+            f,
+            "{}{}: player {} with {} points and {} complete row{}",
+            self.place,
+            suffix,
+            self.player,
+            self.score,
+            self.complete_rows,
+            if self.complete_rows == 1 { "" } else { "s" }
+        )
+    }
+}
+
+pub fn num_complete_rows(board: &PlayerState) -> u8 {
+    board
+        .wall_state
+        .iter()
+        .filter(|row| row.iter().all(|x| *x))
+        .count() as u8
+}
+
+/// Ranks the players by score, and then by the number of complete horizontal rows, as in the
+/// official rules. Players still tied share their place.
+pub fn final_ranking(state: &State) -> Vec<Placing> {
+    let mut placings: Vec<Placing> = state
+        .board_states
+        .iter()
+        .enumerate()
+        .map(|(player_id, board)| Placing {
+            place: 0,
+            player: player_id as u8,
+            score: board.score,
+            complete_rows: num_complete_rows(board),
+        })
+        .collect();
+    placings.sort_by_key(|p| cmp::Reverse((p.score, p.complete_rows)));
+    for i in 0..placings.len() {
+        placings[i].place = if i > 0
+            && (placings[i].score, placings[i].complete_rows)
+                == (placings[i - 1].score, placings[i - 1].complete_rows)
+        {
+            placings[i - 1].place
+        } else {
+            i as u8 + 1
+        };
+    }
+    placings
+}
 
-    for (player_id, board) in state.board_states.iter().enumerate() {
-        state.player_scores[player_id] = if board.score == max_score { 1. } else { 0. };
+/// Sets each player's share of the win from the final ranking. Players tied for first place
+/// share the win equally.
+pub fn set_player_scores(state: &mut State) {
+    for placing in final_ranking(state) {
+        state.player_scores[placing.player as usize] = if placing.place == 1 { 1. } else { 0. };
     }
     let sum_scores: f32 = state.player_scores.iter().sum();
-    for player_id in 0..3 {
-        state.player_scores[player_id] /= sum_scores;
+    for player_score in state.player_scores.iter_mut() {
+        *player_score /= sum_scores;
     }
 }
 
 fn inital_player_state() -> PlayerState {
     PlayerState {
         wall_state: [[false; 5]; 5],
+        wall_colors: [[0; 5]; 5],
         rows: [Row { color: 0, count: 0 }; 5],
         score: 0,
-        floor_tiles: [0; 6],
+        floor: Vec::new(),
     }
 }
-pub fn get_random_initial_state<R: Rng>(rng: &mut R) -> State {
-    let mut board_states = [
-        inital_player_state(),
-        inital_player_state(),
-        inital_player_state(),
-    ];
+pub fn get_random_initial_state<R: Rng>(
+    num_players: u8,
+    rules: Arc<RuleSet>,
+    rng: &mut R,
+) -> State {
+    assert!(
+        num_players >= MIN_PLAYERS && num_players <= MAX_PLAYERS,
+        "Amarillo is played by {} to {} players, not {}.",
+        MIN_PLAYERS,
+        MAX_PLAYERS,
+        num_players
+    );
+    let mut board_states = vec![inital_player_state(); num_players as usize];
     // Give 0th player the start token.
-    board_states[0].floor_tiles[5] = 1;
+    board_states[0].floor.push(FIRST_PLAYER_TOKEN);
     let mut state = State {
         board_states,
-        central_state: CentralState {
-            central_state_arr: [[0; 6]; 8],
-        },
+        central_state: CentralState::new(num_factory_displays(num_players)),
         player_to_play: 0,
-        bag: [20; 5],
+        bag: [rules.tiles_per_color; 5],
         lid: [0; 5],
         is_finished: false,
-        player_scores: [0.; 3],
+        player_scores: vec![0.; num_players as usize],
+        rules,
+        tiling: false,
+        hash: 0,
     };
+    state.hash = compute_hash(&state);
     fill_factory_displays(&mut state, rng);
     state
 }
 
 pub fn fill_factory_displays<R: Rng>(state: &mut State, rng: &mut R) {
-    assert!(has_empty_centre(&state));
+    assert!(!has_tiles_left(&state));
     #[cfg(debug_assertions)]
     check_counts(&state);
-    let mut n: u8 = state.bag.iter().sum();
-    for factory_id in 0..NUM_FACTORY_DISPLAYS {
-        for _ in 0..4 {
+    let num_factory_displays = state.num_factory_displays() as usize;
+    let refill_hash = |state: &State| {
+        state.central_state.central_state_arr[..num_factory_displays]
+            .iter()
+            .enumerate()
+            .fold(
+                supply_hash(&state.bag, &state.lid),
+                |hash, (index, display)| hash ^ display_hash(index, display),
+            )
+    };
+    state.hash ^= refill_hash(state);
+    let bag_size = |bag: &[u8; 5]| bag.iter().map(|count| u32::from(*count)).sum::<u32>();
+    let mut n = bag_size(&state.bag);
+    for factory_id in 0..state.num_factory_displays() {
+        for _ in 0..state.rules.tiles_per_display {
             if n == 0 {
                 for i in 0..5 {
                     state.bag[i] = state.lid[i];
                     state.lid[i] = 0;
-                    n = state.bag.iter().sum();
+                    n = bag_size(&state.bag);
                 }
             }
             if n != 0 {
@@ -592,142 +1251,501 @@ pub fn fill_factory_displays<R: Rng>(state: &mut State, rng: &mut R) {
             }
         }
     }
+    state.hash ^= refill_hash(state);
 }
 
-fn has_empty_centre(state: &State) -> bool {
-    for i in 0..=NUM_FACTORY_DISPLAYS {
-        for j in 0..6 {
-            if state.central_state.central_state_arr[i as usize][j] > 0 {
-                return false;
-            }
-        }
-    }
-    true
+/// Whether no tiles have been taken yet this round: the pattern lines are empty and the floor
+/// lines hold nothing but the first player token.
+fn is_first_move(state: &State) -> bool {
+    state.board_states.iter().all(|board| {
+        board.rows.iter().all(|row| row.count == 0)
+            && board.floor.iter().all(|tile| *tile == FIRST_PLAYER_TOKEN)
+    })
+}
+
+/// Whether there are tiles on the factory displays or in the centre, which means the round isn't
+/// over. The first player token doesn't count.
+fn has_tiles_left(state: &State) -> bool {
+    state
+        .central_state
+        .central_state_arr
+        .iter()
+        .any(|display| display[..5].iter().any(|count| *count > 0))
 }
 
 /// Check that the action is valid
 pub fn is_valid_action(state: &State, action: Action) -> bool {
-    let valid_actions = get_valid_actions(&state);
-    for valid_action in valid_actions {
-        if action == valid_action {
-            return true;
-        }
-    }
-    false
+    let mut is_valid = false;
+    for_each_valid_action(state, |valid_action| is_valid |= valid_action == action);
+    is_valid
 }
-/// Plays an action inplace.
-/// Doesn't refill the factory displays.
-pub fn step(mut state: State, action: Action, do_check_counts: bool) -> (State, bool) {
-    debug_assert!(
-        is_valid_action(&state, action),
-        format!(
-            "Tried to play invalid action {} in state:\n{}",
-            action.to_string(),
-            state.to_string()
-        )
-    );
-    if do_check_counts {
-        #[cfg(debug_assertions)]
-        check_counts(&state);
-    }
-    state.player_scores = [0., 0., 0.];
-    let display_number = action.display_number;
-    let color = action.color;
-    let num_tiles = state.central_state.central_state_arr[display_number as usize][color as usize];
-    let row_id = action.row_id;
+/// Takes tiles from a display and puts them on a row or the floor.
+fn take_tiles(state: &mut State, display_number: u8, color: u8, row_id: u8) {
+    let num_factory_displays = state.num_factory_displays();
+    let display_index = state.central_state.display_index(display_number);
+    let num_tiles = state.central_state.central_state_arr[display_index][color as usize];
     {
         let central_state_arr = &mut state.central_state.central_state_arr;
-        if display_number < NUM_FACTORY_DISPLAYS {
+        if display_number != CENTRE {
             // Move non-requested tiles from the factory to the dump.
-            for color in 0..6 {
-                if color as u8 == action.color {
+            for other_color in 0..6 {
+                if other_color as u8 == color {
                     continue;
                 }
-                central_state_arr[NUM_FACTORY_DISPLAYS as usize][color] +=
-                    central_state_arr[display_number as usize][color];
-                central_state_arr[display_number as usize][color] = 0;
+                central_state_arr[num_factory_displays as usize][other_color] +=
+                    central_state_arr[display_index][other_color];
+                central_state_arr[display_index][other_color] = 0;
             }
         }
     }
 
-    if is_first_move(&state) {
-        let floor_tiles = &mut state.board_states[state.player_to_play as usize].floor_tiles;
-        // Check that the player to play has the first player token.
-        debug_assert!(
-            floor_tiles[5] == 1,
-            format!("It's the first move, but the player to play doesn't have the first player token in state:\n{}", state.to_string())
-        );
+    let capacity = state.rules.floor_capacity();
+    let is_first_move = is_first_move(state);
+    let board = &mut state.board_states[state.player_to_play as usize];
+    if is_first_move && board.floor.contains(&FIRST_PLAYER_TOKEN) {
         // Move the first player token to the dump.
-        floor_tiles[5] = 0;
-        state.central_state.central_state_arr[NUM_FACTORY_DISPLAYS as usize][5] = 1;
+        board.floor.retain(|tile| *tile != FIRST_PLAYER_TOKEN);
+        state.central_state.central_state_arr[num_factory_displays as usize]
+            [FIRST_PLAYER_TOKEN as usize] = 1;
     }
-    if state.central_state.central_state_arr[display_number as usize][5] > 0 {
+    if state.central_state.central_state_arr[display_index][FIRST_PLAYER_TOKEN as usize] > 0 {
         // If the player token is in the dump, give it to the first player.
-        let floor_tiles = &mut state.board_states[state.player_to_play as usize].floor_tiles;
-        floor_tiles[5] = 1;
-        state.central_state.central_state_arr[display_number as usize][5] = 0;
+        put_token_on_floor(board, &mut state.lid, capacity);
+        state.central_state.central_state_arr[display_index][FIRST_PLAYER_TOKEN as usize] = 0;
     }
 
-    // Give the player the requested tiles.
-    if row_id < 5 {
-        let mut row = &mut state.board_states[state.player_to_play as usize].rows[row_id as usize];
-        row.count += num_tiles;
-        row.color = action.color;
+    // Give the player the requested tiles. The ones that don't fit on the row go on the floor.
+    let num_on_floor = if row_id < 5 {
+        let row = &mut board.rows[row_id as usize];
+        let num_on_row = cmp::min(num_tiles, row_id + 1 - row.count);
+        row.count += num_on_row;
+        row.color = color;
+        num_tiles - num_on_row
     } else {
-        state.board_states[state.player_to_play as usize].floor_tiles[action.color as usize] +=
-            num_tiles;
+        num_tiles
+    };
+    put_on_floor(board, &mut state.lid, color, num_on_floor, capacity);
+    state.central_state.central_state_arr[display_index][color as usize] = 0;
+
+    state.player_to_play = (state.player_to_play + 1) % state.num_players();
+}
+
+/// Plays an action, checking first that it's legal. Unlike `step`, the state is left untouched
+/// if it isn't.
+///
+/// Returns the new state and whether the round is over, as `step` does.
+pub fn try_step(state: &State, action: Action) -> Result<(State, bool), GameError> {
+    try_step_with_log(state, action, &mut ())
+}
+
+/// `try_step`, giving the scoring events to `log`.
+pub fn try_step_with_log<L: ScoreLog + ?Sized>(
+    state: &State,
+    action: Action,
+    log: &mut L,
+) -> Result<(State, bool), GameError> {
+    if state.is_finished {
+        return Err(GameError::GameFinished);
     }
-    state.central_state.central_state_arr[display_number as usize][action.color as usize] = 0;
-    if do_check_counts {
-        #[cfg(debug_assertions)]
-        check_counts(&state);
+    if !is_valid_action(state, action) {
+        return Err(GameError::IllegalAction(action));
     }
+    Ok(step_with_log(state.clone(), action, true, log))
+}
 
-    // Move overflow tiles to the floor.
-    if row_id < 5 {
-        let board = &mut state.board_states[state.player_to_play as usize];
-        let row_max = row_id + 1;
-        let overflow_num = board.rows[row_id as usize].count as i8 - row_max as i8;
-        if overflow_num > 0 {
-            board.rows[row_id as usize].count = row_max;
-            board.floor_tiles[color as usize] += overflow_num as u8;
-        }
-    }
+/// Plays an action inplace.
+/// Doesn't refill the factory displays.
+///
+/// The action must be legal: this is only checked in debug builds. Use `try_step` for actions
+/// that haven't been checked, e.g. ones entered by a user.
+///
+/// Returns the new state and whether the round is over, in which case the factory displays
+/// need refilling unless the game is finished.
+pub fn step(state: State, action: Action, do_check_counts: bool) -> (State, bool) {
+    step_with_log(state, action, do_check_counts, &mut ())
+}
 
-    state.player_to_play = (state.player_to_play + 1) % 3;
+/// `step`, giving the scoring events to `log`.
+pub fn step_with_log<L: ScoreLog + ?Sized>(
+    mut state: State,
+    action: Action,
+    do_check_counts: bool,
+    log: &mut L,
+) -> (State, bool) {
+    let round_over = step_in_place(&mut state, action, do_check_counts, log);
+    (state, round_over)
+}
 
+/// Plays an action, changing the state in place. See `step`.
+fn step_in_place<L: ScoreLog + ?Sized>(
+    state: &mut State,
+    action: Action,
+    do_check_counts: bool,
+    log: &mut L,
+) -> bool {
+    debug_assert!(
+        is_valid_action(state, action),
+        format!(
+            "Tried to play invalid action {} in state:\n{}",
+            action.to_string(),
+            state.to_string()
+        )
+    );
     if do_check_counts {
         #[cfg(debug_assertions)]
-        check_counts(&state);
+        check_counts(state);
+    }
+    for player_score in state.player_scores.iter_mut() {
+        *player_score = 0.;
     }
-    let empty_centre = has_empty_centre(&state);
-    if empty_centre {
-        score_and_reset(&mut state);
-        state.is_finished = is_finished(&state);
+    let round_over = match action {
+        Action::Take {
+            display_number,
+            color,
+            row_id,
+        } => {
+            // Only the player's board, the display, the centre, the lid and the turn change.
+            let player = state.player_to_play;
+            let display_index = state.central_state.display_index(display_number);
+            let centre = state.num_factory_displays() as usize;
+            let changed_hash = |state: &State| {
+                let arr = &state.central_state.central_state_arr;
+                let mut hash = board_hash(player, &state.board_states[player as usize])
+                    ^ display_hash(display_index, &arr[display_index])
+                    ^ supply_hash(&state.bag, &state.lid)
+                    ^ turn_hash(state);
+                if display_index != centre {
+                    hash ^= display_hash(centre, &arr[centre]);
+                }
+                hash
+            };
+            state.hash ^= changed_hash(state);
+            take_tiles(state, display_number, color, row_id);
+            state.hash ^= changed_hash(state);
+            if do_check_counts {
+                #[cfg(debug_assertions)]
+                check_counts(state);
+            }
+            if has_tiles_left(state) {
+                false
+            } else if state.rules.free_wall {
+                state.tiling = true;
+                advance_tiling(state, log)
+            } else {
+                score_and_reset(state, log);
+                true
+            }
+        }
+        Action::Place { row_id, col_id } => {
+            let board = &mut state.board_states[state.player_to_play as usize];
+            move_row_to_wall(
+                state.player_to_play,
+                board,
+                &mut state.lid,
+                row_id,
+                col_id,
+                log,
+            );
+            advance_tiling(state, log)
+        }
+    };
+    if round_over {
+        state.is_finished = is_finished(state);
         if state.is_finished {
-            score_bonuses(&mut state);
+            score_bonuses(state, log);
         }
     }
+    if round_over || state.tiling {
+        // The end of a round changes most of the state.
+        state.hash = compute_hash(state);
+    }
     if do_check_counts {
         #[cfg(debug_assertions)]
-        check_counts(&state);
+        check_counts(state);
+        debug_assert_eq!(state.hash, compute_hash(state));
     }
-    if empty_centre {
+    if round_over {
         // Check there are no floor tiles.
         for player_state in state.board_states.iter() {
-            for (color, count) in player_state.floor_tiles.iter().enumerate() {
-                if color == 5 {
-                    continue;
+            debug_assert!(
+                player_state
+                    .floor
+                    .iter()
+                    .all(|tile| *tile == FIRST_PLAYER_TOKEN),
+                format!(
+                    "Centre is empty but there are floor tiles for state:\n{}",
+                    state.to_string()
+                )
+            );
+        }
+    }
+    round_over
+}
+
+/// What `apply` changed, which `unapply` puts back.
+#[derive(Clone, Debug)]
+pub struct Undo {
+    change: Change,
+    round_over: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+    /// A take that left tiles to play for. Only the player's row and floor, the display, the
+    /// centre, the lid, the turn and the hash changed.
+    Take {
+        player: u8,
+        row_id: u8,
+        row: Row,
+        /// The length of the floor and its last tile, which goes to the lid if the first player
+        /// token doesn't fit, or is the token going to the centre on the round's first move.
+        floor_len: usize,
+        floor_last: Option<u8>,
+        display_index: usize,
+        display: [u8; 6],
+        centre: [u8; 6],
+        lid: [u8; 5],
+        hash: u64,
+    },
+    /// An action that ended the round or was part of tiling, which changes most of the state.
+    State(Box<State>),
+}
+
+impl Undo {
+    /// Whether the action ended the round, as returned by `step`.
+    pub fn round_over(&self) -> bool {
+        self.round_over
+    }
+}
+
+/// Plays an action in place, returning what's needed to take it back with `unapply`. Otherwise
+/// the same as `step`, including the checks.
+///
+/// Most actions only change a few parts of the state, so this is cheaper than cloning it. The
+/// ones that end the round keep a copy of the whole state.
+pub fn apply(state: &mut State, action: Action) -> Undo {
+    let change = match action {
+        Action::Take {
+            display_number,
+            color,
+            row_id,
+        } if !state.tiling && state.player_scores.iter().all(|score| *score == 0.) => {
+            let arr = &state.central_state.central_state_arr;
+            let display_index = state.central_state.display_index(display_number);
+            let num_tiles_left: u32 = arr
+                .iter()
+                .map(|tiles| tiles[..5].iter().map(|count| *count as u32).sum::<u32>())
+                .sum();
+            if num_tiles_left > arr[display_index][color as usize] as u32 {
+                let board = &state.board_states[state.player_to_play as usize];
+                Change::Take {
+                    player: state.player_to_play,
+                    row_id,
+                    row: board.rows[cmp::min(row_id, 4) as usize],
+                    floor_len: board.floor.len(),
+                    floor_last: board.floor.last().cloned(),
+                    display_index,
+                    display: arr[display_index],
+                    centre: arr[state.num_factory_displays() as usize],
+                    lid: state.lid,
+                    hash: state.hash,
+                }
+            } else {
+                Change::State(Box::new(state.clone()))
+            }
+        }
+        _ => Change::State(Box::new(state.clone())),
+    };
+    let round_over = step_in_place(state, action, true, &mut ());
+    Undo { change, round_over }
+}
+
+/// Takes back the action `apply` played, restoring the state exactly.
+pub fn unapply(state: &mut State, undo: Undo) {
+    match undo.change {
+        Change::Take {
+            player,
+            row_id,
+            row,
+            floor_len,
+            floor_last,
+            display_index,
+            display,
+            centre,
+            lid,
+            hash,
+        } => {
+            let board = &mut state.board_states[player as usize];
+            if row_id < 5 {
+                board.rows[row_id as usize] = row;
+            }
+            // Only the last tile on the floor can have been removed.
+            board.floor.truncate(floor_len.saturating_sub(1));
+            board.floor.extend(floor_last);
+            let centre_index = state.num_factory_displays() as usize;
+            let arr = &mut state.central_state.central_state_arr;
+            arr[display_index] = display;
+            arr[centre_index] = centre;
+            state.lid = lid;
+            state.player_to_play = player;
+            state.hash = hash;
+        }
+        Change::State(previous) => *state = *previous,
+    }
+    debug_assert_eq!(state.hash, compute_hash(state));
+}
+
+/// Plays random games for the tests, five with each number of players, refilling the factory
+/// displays between rounds. `check` is called after every action with the state before it, the
+/// action, the state after it and whether the round is over.
+///
+/// Random moves can leave a free wall where no row can be completed, and then the game never
+/// ends, so each game stops after 100 rounds.
+#[cfg(test)]
+pub(crate) fn play_random_games<F>(rules: RuleSet, mut check: F)
+where
+    F: FnMut(&State, Action, &State, bool),
+{
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    let rules = Arc::new(rules);
+    let mut rng = SmallRng::seed_from_u64(1);
+    for num_players in MIN_PLAYERS..=MAX_PLAYERS {
+        for _ in 0..5 {
+            let mut state = get_random_initial_state(num_players, rules.clone(), &mut rng);
+            let mut num_rounds = 0;
+            while !state.is_finished && num_rounds < 100 {
+                let valid_actions = get_valid_actions(&state);
+                let action = valid_actions[rng.gen_range(0, valid_actions.len())];
+                let (next_state, round_over) = step(state.clone(), action, true);
+                check(&state, action, &next_state, round_over);
+                state = next_state;
+                if round_over {
+                    num_rounds += 1;
+                    if !state.is_finished {
+                        fill_factory_displays(&mut state, &mut rng);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Plays random games with `apply`, checking each action against `step` and that `unapply`
+    /// takes back every action of each round, including the tiling in the free wall variant.
+    fn check_apply_unapply(free_wall: bool) {
+        let mut rules = RuleSet::default();
+        rules.free_wall = free_wall;
+        // The state at the start of the round, the state the actions since have been applied
+        // to, and the actions and their undos.
+        let mut round: Option<(State, State, Vec<Action>, Vec<Undo>)> = None;
+        play_random_games(rules, |state, action, next_state, round_over| {
+            let (round_start, applied, actions, undos) =
+                round.get_or_insert_with(|| (state.clone(), state.clone(), Vec::new(), Vec::new()));
+            let undo = apply(applied, action);
+            assert_eq!(applied, next_state, "after {}", action);
+            assert_eq!(applied.hash, compute_hash(applied));
+            assert_eq!(undo.round_over(), round_over);
+            actions.push(action);
+            undos.push(undo);
+            if round_over {
+                while let Some(undo) = undos.pop() {
+                    unapply(applied, undo);
                 }
-                debug_assert!(
-                    *count == 0,
-                    format!(
-                        "Centre is empty but there are floor tiles for state:\n{}",
-                        state.to_string()
-                    )
-                );
+                assert_eq!(applied, round_start);
+                assert_eq!(applied.hash, round_start.hash);
+                for action in actions.iter() {
+                    apply(applied, *action);
+                }
+                assert_eq!(applied, next_state);
+                round = None;
+            }
+        });
+    }
+
+    #[test]
+    fn apply_unapply_round_trip() {
+        check_apply_unapply(false);
+    }
+
+    #[test]
+    fn apply_unapply_round_trip_free_wall() {
+        check_apply_unapply(true);
+    }
+
+    #[test]
+    fn validate_state_checks_the_rules() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut rules = RuleSet::default();
+        rules.tiles_per_color = 60;
+        let state = get_random_initial_state(3, Arc::new(rules), &mut rng);
+        assert!(validate_state(&state).is_err());
+
+        let mut rules = RuleSet::default();
+        rules.floor_penalties.clear();
+        let state = get_random_initial_state(3, Arc::new(rules), &mut rng);
+        assert!(validate_state(&state).is_err());
+    }
+
+    /// A 3-player state where each player has the given score and number of complete rows.
+    fn end_state(scores_and_rows: &[(i32, usize)]) -> State {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut state = get_random_initial_state(3, Arc::new(RuleSet::default()), &mut rng);
+        for (board, (score, num_rows)) in state.board_states.iter_mut().zip(scores_and_rows) {
+            board.score = *score;
+            for row in board.wall_state[..*num_rows].iter_mut() {
+                *row = [true; 5];
             }
         }
+        state
+    }
+
+    /// The players in order of their placings, with their places.
+    fn places(state: &State) -> Vec<(u8, u8)> {
+        final_ranking(state)
+            .iter()
+            .map(|placing| (placing.player, placing.place))
+            .collect()
+    }
+
+    #[test]
+    fn final_ranking_breaks_ties_on_rows() {
+        let mut state = end_state(&[(20, 1), (20, 2), (25, 0)]);
+        assert_eq!(places(&state), vec![(2, 1), (1, 2), (0, 3)]);
+        set_player_scores(&mut state);
+        assert_eq!(state.player_scores, vec![0., 0., 1.]);
+
+        let mut state = end_state(&[(20, 1), (20, 2), (15, 0)]);
+        assert_eq!(places(&state), vec![(1, 1), (0, 2), (2, 3)]);
+        set_player_scores(&mut state);
+        assert_eq!(state.player_scores, vec![0., 1., 0.]);
+    }
+
+    #[test]
+    fn final_ranking_shares_true_ties() {
+        let mut state = end_state(&[(10, 0), (20, 1), (20, 1)]);
+        assert_eq!(places(&state), vec![(1, 1), (2, 1), (0, 3)]);
+        set_player_scores(&mut state);
+        assert_eq!(state.player_scores, vec![0., 0.5, 0.5]);
+
+        let state = end_state(&[(30, 0), (20, 1), (20, 1)]);
+        assert_eq!(places(&state), vec![(0, 1), (1, 2), (2, 2)]);
+    }
+
+    /// The most tiles of each color that the rules allow.
+    #[test]
+    fn games_with_the_most_tiles_play_to_the_end() {
+        let mut rules = RuleSet::default();
+        rules.tiles_per_color = 50;
+        play_random_games(rules, |state, _, _, _| {
+            assert_eq!(validate_state(state), Ok(()))
+        });
     }
-    (state, empty_centre)
 }
@@ -0,0 +1,51 @@
+//! The engine and search behind amarillo, a tile game inspired by Azul.
+//!
+//! * `game` runs a game: it plays actions, refills the factory displays between rounds and keeps
+//!   the history of moves. Front ends should use it rather than the lower-level functions.
+//! * `game_state` holds the state of a game, the actions and `step`/`try_step` to play them
+//!   (or `apply`/`unapply` to play and take them back in place), and `fill_factory_displays` to
+//!   refill the displays between rounds.
+//! * `scoring` describes where each player's points came from, as the game is played.
+//! * `rules` configures the numbers of tiles, the scoring and the variant.
+//! * `notation` writes and parses actions and whole positions.
+//! * `record` saves games so they can be resumed and replayed.
+//! * `packed` is a compact copy of the state for fast simulation, used by the search's random
+//!   playouts (the tree itself holds `State`s).
+//! * `zobrist` hashes states, for recognising positions reached by different move orders.
+//! * `mcts` chooses moves with a Monte Carlo tree search, on one thread or several, guided by one
+//!   of the value functions in `value_fns`. A `Searcher` keeps the tree from one move to the next.
+//!
+//! A game against itself:
+//!
+//! ```no_run
+//! use amarillo::game::Game;
+//! use amarillo::mcts::{make_move, SearchConfig};
+//! use amarillo::rules::RuleSet;
+//! use amarillo::value_fns::ValueFunctionScore;
+//! use rand::SeedableRng;
+//! use std::sync::Arc;
+//!
+//! let mut game = Game::new(2, Arc::new(RuleSet::default()), 1);
+//! let mut rng = rand::rngs::SmallRng::seed_from_u64(2);
+//! let mut vf = ValueFunctionScore::new();
+//! let config = SearchConfig {
+//!     time_limit: None,
+//!     max_playouts: Some(1000),
+//!     ..SearchConfig::default()
+//! };
+//! while !game.is_finished() {
+//!     let action = make_move(game.state(), &config, &mut vf, &mut rng).unwrap();
+//!     game.play(action).unwrap();
+//! }
+//! println!("{}\nScores: {:?}", game.state(), game.scores());
+//! ```
+pub mod game;
+pub mod game_state;
+pub mod mcts;
+pub mod notation;
+pub mod packed;
+pub mod record;
+pub mod rules;
+pub mod scoring;
+pub mod value_fns;
+pub mod zobrist;
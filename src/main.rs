@@ -1,126 +1,357 @@
 use rand::SeedableRng;
 use std::io::Write;
+use std::sync::Arc;
+use structopt::StructOpt;
 
-mod game_state;
-mod mcts;
-mod value_fns;
-use game_state::*;
-use mcts::*;
-use value_fns::*;
+use amarillo::game::*;
+use amarillo::game_state::*;
+use amarillo::mcts::*;
+use amarillo::notation::*;
+use amarillo::record::*;
+use amarillo::rules::*;
+use amarillo::value_fns::*;
 
-fn read_char() -> Option<char> {
-    let mut s = String::new();
-    std::io::stdin().read_line(&mut s).expect("Bad input.");
-    s.chars().next()
+#[derive(StructOpt)]
+#[structopt(
+    name = "amarillo",
+    about = "A tile game against Monte Carlo tree search opponents."
+)]
+struct Opt {
+    /// Number of players, including you (2 to 4).
+    #[structopt(short, long, default_value = "3")]
+    players: u8,
+    /// JSON file with the rule set to play with. Defaults to the official rules.
+    #[structopt(long)]
+    rules: Option<String>,
+    /// Play the free placement ("grey wall") variant.
+    #[structopt(long)]
+    free_wall: bool,
+    /// File to save the game record to after every move.
+    #[structopt(long)]
+    record: Option<String>,
+    /// Resume the game saved in this record file. The game carries on being saved to it unless
+    /// --record is given.
+    #[structopt(long)]
+    resume: Option<String>,
+    /// Step through the game saved in this record file instead of playing.
+    #[structopt(long)]
+    replay: Option<String>,
+    /// Start from this position (see the notation module) instead of a new game.
+    #[structopt(long)]
+    position: Option<String>,
+    /// Seed for the random number generator, to play the same game again. A random seed is used
+    /// (and printed) if none is given.
+    #[structopt(long)]
+    seed: Option<u64>,
+    /// Number of playouts the computer players run for each move, instead of searching for a
+    /// fixed time. With a seed, this makes their moves reproducible.
+    #[structopt(long)]
+    playouts: Option<i32>,
+    /// Number of threads the computer players search with.
+    #[structopt(long, default_value = "1")]
+    threads: usize,
+    /// Search a separate tree on each thread and combine them at the end, instead of sharing
+    /// one tree between the threads. With a seed and --playouts, this keeps the computer
+    /// players' moves reproducible.
+    #[structopt(long)]
+    root_parallel: bool,
+    /// Don't search moves that take from a factory display holding the same tiles as another.
+    #[structopt(long)]
+    canonical_moves: bool,
+    /// Print the playouts and scores of the moves the computer players considered.
+    #[structopt(long)]
+    verbose: bool,
 }
 
-fn options_to_str<T: ToString>(options: &std::collections::BTreeSet<T>) -> String {
-    let mut output = String::new();
-    // let output_vec = Vec::new();
-    for (idx, option) in options.iter().enumerate() {
-        // output_vec.push(&option.to_string());
-        output.push_str(&option.to_string());
-        if idx as isize == options.len() as isize - 2 {
-            output.push_str(" or ");
-        } else if idx < options.len() - 1 {
-            output.push_str(", ");
-        }
-    }
-    output
+fn load_record(filename: &str) -> GameRecord {
+    match GameRecord::load(filename) {
+        Ok(record) => record,
+        Err(err) => {
+            eprintln!("Could not load the game record from {}: {}", filename, err);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn prompt<T: ToString>(thing: &str, options: &std::collections::BTreeSet<T>) -> Option<char> {
-    if options.len() == 1 {
-        let option = options.iter().next()?.to_string();
-        println!("You are forced to choose {} {}.", thing, option);
-        return option.chars().next();
-    }
-    print!("Select {} ({}): ", thing, options_to_str(&options));
-    std::io::stdout().flush().unwrap();
-    read_char()
+fn save_record(record: &GameRecord, filename: &Option<String>) {
+    if let Some(filename) = filename {
+        if let Err(err) = record.save(filename) {
+            eprintln!("Could not save the game record to {}: {}", filename, err);
+        }
+    }
 }
 
-fn input_move_opt(state: &State) -> Option<Action> {
-    let mut valid_actions = get_valid_actions(&state);
+/// Prints each state of a recorded game, waiting for the enter key in between.
+fn replay_game(record: &GameRecord) {
+    println!("{:}", record.initial_state.to_string());
+    for (entry, state) in record.replay() {
+        read_line();
+        match entry {
+            RecordEntry::Action(action) => println!("{}", action),
+            RecordEntry::Refill(_) => println!("The factory displays are refilled."),
+        }
+        println!("{:}", state.to_string());
+    }
+    if record.final_scores.is_some() {
+        print_ranking(&final_ranking(&record.final_state()));
+    }
+}
 
-    let mut display_numbers = std::collections::BTreeSet::new();
-    for action in &valid_actions {
-        display_numbers.insert(action.display_number);
+fn print_ranking(ranking: &[Placing]) {
+    println!("Final ranking:");
+    for placing in ranking {
+        println!("{}", placing);
     }
-    let display_number = prompt("display", &display_numbers).and_then(|x| x.to_digit(10))? as u8;
-    display_numbers.get(&display_number)?;
-    valid_actions.retain(|action| action.display_number == display_number);
+}
 
-    let mut colors = std::collections::BTreeSet::new();
-    for action in &valid_actions {
-        colors.insert(action.color);
+/// Reads a line from stdin, exiting at the end of the input.
+fn read_line() -> String {
+    let mut s = String::new();
+    let num_bytes = std::io::stdin().read_line(&mut s).expect("Bad input.");
+    if num_bytes == 0 {
+        std::process::exit(0);
     }
-    let mut color_chars = std::collections::BTreeSet::new();
-    for color in &colors {
-        color_chars.insert(COLOR_NAMES[*color as usize].to_ascii_lowercase());
+    s.trim().to_string()
+}
+
+/// Prints how the scores changed, if the round is over.
+fn print_round_summary(events: &[GameEvent]) {
+    for event in events {
+        if let GameEvent::RoundEnded { round } = event {
+            println!("Round {} is over.", round);
+        }
     }
-    let color_char = prompt("color", &color_chars)?;
-    let mut color_opt = None;
-    for (color_int, color_char_val) in COLOR_NAMES.iter().enumerate() {
-        if color_char_val.to_ascii_lowercase() == color_char {
-            color_opt = Some(color_int);
+    for event in events {
+        match event {
+            GameEvent::Scored(score_event) => println!("  {}", score_event),
+            GameEvent::GameOver { scores } => println!("The game is over. Scores: {:?}", scores),
+            _ => {}
         }
     }
-    let color_char = color_opt?;
-    let color = color_char as u8;
-    colors.get(&color)?;
-    valid_actions.retain(|action| action.color == color);
+}
+
+/// The player you play as.
+const HUMAN: u8 = 0;
 
-    let mut row_ids = std::collections::BTreeSet::new();
-    for action in &valid_actions {
-        row_ids.insert(action.row_id);
+/// What you entered at the prompt.
+enum Input {
+    Move(Action),
+    Undo,
+    Redo,
+}
+
+/// Whether you have a choice to make, rather than a forced move or none.
+fn is_human_decision(state: &State) -> bool {
+    !state.is_finished && state.player_to_play == HUMAN && count_legal_actions(state) > 1
+}
+
+/// Takes back moves until you're at your previous decision. Does nothing if there isn't one.
+fn undo_to_decision(game: &mut Game) -> bool {
+    let mut num_undone = 0;
+    while game.undo().is_some() {
+        num_undone += 1;
+        if is_human_decision(game.state()) {
+            return true;
+        }
     }
-    let row_id = prompt("destination", &row_ids).and_then(|x| x.to_digit(10))? as u8;
-    row_ids.get(&row_id)?;
+    for _ in 0..num_undone {
+        game.redo();
+    }
+    false
+}
 
-    Some(Action {
-        display_number,
-        color,
-        row_id,
-    })
+/// Plays the moves taken back again until you're at your next decision.
+fn redo_to_decision(game: &mut Game) -> bool {
+    if game.redo().is_none() {
+        return false;
+    }
+    while game.can_redo() && !is_human_decision(game.state()) {
+        game.redo();
+    }
+    true
 }
 
-fn input_move(state: &State) -> Action {
+fn input_move(state: &State) -> Input {
+    let valid_actions = get_valid_actions(&state);
+    if valid_actions.len() == 1 {
+        println!("You are forced to play {}.", valid_actions[0]);
+        return Input::Move(valid_actions[0]);
+    }
     loop {
-        if let Some(action) = input_move_opt(&state) {
-            return action;
+        print!(
+            "Your move, e.g. {} (? lists the legal moves, pos prints the position, undo and redo \
+             take back and replay moves): ",
+            valid_actions[0]
+        );
+        std::io::stdout().flush().unwrap();
+        let line = read_line();
+        if line == "?" {
+            let notations: Vec<String> = valid_actions.iter().map(|a| a.to_string()).collect();
+            println!("{}", notations.join(" "));
+            continue;
+        }
+        if line == "pos" {
+            println!("{}", to_position(&state));
+            continue;
+        }
+        if line == "undo" {
+            return Input::Undo;
+        }
+        if line == "redo" {
+            return Input::Redo;
+        }
+        match line.parse::<Action>() {
+            Ok(action) if valid_actions.contains(&action) => return Input::Move(action),
+            Ok(action) => println!("{} is not a legal move.", action),
+            Err(err) => println!("{}.", err),
         }
     }
 }
 
 fn main() {
-    let mut rng = rand::rngs::SmallRng::from_entropy();
-    let mut vf = ValueFunctionTFV2::new();
-    let time_limit = 0.4;
-    let mut state = get_random_initial_state(&mut rng);
+    let opt = Opt::from_args();
+    if let Some(filename) = &opt.replay {
+        replay_game(&load_record(filename));
+        return;
+    }
+    if let Err(err) = check_num_players(opt.players) {
+        eprintln!("{}.", err);
+        std::process::exit(1);
+    }
+    let rules = match RuleSet::from_options(opt.rules.as_deref(), opt.free_wall) {
+        Ok(rules) => rules,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    println!("Seed: {}", seed);
+    // The search has its own generator, so that the refills don't depend on how many playouts
+    // were run.
+    let mut search_rng = rand::rngs::SmallRng::seed_from_u64(seed.wrapping_add(1));
+    let rules = Arc::new(rules);
+    let game = match (&opt.resume, &opt.position) {
+        (Some(filename), _) => Game::from_record(load_record(filename), seed)
+            .map_err(|err| format!("Could not resume the game: {}", err)),
+        (None, Some(position)) => parse_position(position, rules)
+            .map_err(|err| err.to_string())
+            .and_then(|state| Game::from_state(state, seed).map_err(|err| err.to_string())),
+        (None, None) => Ok(Game::new(opt.players, rules, seed)),
+    };
+    let mut game = match game {
+        Ok(game) => game,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let record_filename = opt.record.clone().or_else(|| opt.resume.clone());
+    // The value network was trained on 3-player games.
+    let network = if game.state().num_players() == 3 {
+        match ValueFunctionTFV2::new() {
+            Ok(vf) => Some(vf),
+            Err(err) => {
+                eprintln!(
+                    "{}. The computer players will judge positions by score.",
+                    err
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let use_network = network.is_some();
+    let mut vf: Box<dyn ValueFunction> = match network {
+        Some(vf) => Box::new(vf),
+        None => Box::new(ValueFunctionScore::new()),
+    };
+    // Each search thread needs a value function of its own.
+    let make_vf: Arc<ValueFunctionFactory> = Arc::new(move || {
+        if use_network {
+            Ok(Box::new(ValueFunctionTFV2::new()?))
+        } else {
+            Ok(Box::new(ValueFunctionScore::new()))
+        }
+    });
+    let mut config = SearchConfig {
+        max_playouts: opt.playouts,
+        num_threads: opt.threads,
+        parallelism: if opt.root_parallel {
+            Parallelism::Root
+        } else {
+            Parallelism::Tree
+        },
+        make_vf: Some(make_vf),
+        moves: if opt.canonical_moves {
+            MoveGeneration::Canonical
+        } else {
+            MoveGeneration::All
+        },
+        verbose: opt.verbose,
+        ..SearchConfig::default()
+    };
+    if opt.playouts.is_some() {
+        config.time_limit = None;
+    }
+    // Each computer player keeps its search tree from one move to the next.
+    let searchers: Result<Vec<_>, _> = (0..game.state().num_players())
+        .map(|_| Searcher::new(&config))
+        .collect();
+    let mut searchers = match searchers {
+        Ok(searchers) => searchers,
+        Err(err) => {
+            eprintln!(
+                "Could not make the search threads' value functions: {}",
+                err
+            );
+            std::process::exit(1);
+        }
+    };
     loop {
+        let state = game.state();
         println!("{:}", state.to_string());
         if state.is_finished {
+            print_ranking(&final_ranking(state));
             break;
         }
-        let action = if state.player_to_play == 0 {
-            input_move(&state)
+        let action = if state.player_to_play == HUMAN {
+            match input_move(state) {
+                Input::Move(action) => action,
+                Input::Undo => {
+                    if !undo_to_decision(&mut game) {
+                        println!("There are no moves of yours to take back.");
+                    }
+                    save_record(game.record(), &record_filename);
+                    continue;
+                }
+                Input::Redo => {
+                    if !redo_to_decision(&mut game) {
+                        println!("There are no moves to play again.");
+                    }
+                    save_record(game.record(), &record_filename);
+                    continue;
+                }
+            }
         } else {
-            make_move(
-                &state,
-                std::time::Duration::from_nanos((time_limit * 1e9) as u64),
-                &mut vf,
-            )
+            let searcher = &mut searchers[state.player_to_play as usize];
+            let (action, stats) = searcher.search(state, &config, &mut *vf, &mut search_rng);
+            println!(
+                "{} playouts ({} kept from the last move){}.",
+                stats.num_playouts,
+                stats.retained_playouts,
+                if stats.is_complete {
+                    " (tree fully explored)"
+                } else {
+                    ""
+                }
+            );
+            action
         };
-        println!("{:}", action.to_string());
-        let (new_state, empty_centre) = step(state, action, true);
-        state = new_state;
-        if empty_centre && !state.is_finished {
-            fill_factory_displays(&mut state, &mut rng);
-        }
-    }
-    let mut scores = [0; 3];
-    for (player_num, board) in state.board_states.iter().enumerate() {
-        scores[player_num] = board.score;
+        println!("Player {} plays {}.", state.player_to_play, action);
+        let events = game.play(action).expect("The move was checked.");
+        print_round_summary(&events);
+        save_record(game.record(), &record_filename);
     }
 }
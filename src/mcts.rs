@@ -1,166 +1,432 @@
+//! Monte Carlo tree search for choosing the computer players' moves.
 use crate::game_state::*;
+use crate::packed::PackedState;
 use crate::value_fns::*;
-use rand::Rng;
-use std::{thread, time};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasherDefault;
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-/// Game tree starting from a particular action.
+/// A map with a fixed hasher, so that it's iterated in the same order on every run and searches
+/// with the same random number generator seed give the same result.
+type ActionMap<V> = HashMap<Action, V, BuildHasherDefault<DefaultHasher>>;
+
+/// The statistics of an action from a state.
 #[derive(Default, Debug)]
-struct ActionTree {
-    /// The number of playouts in the tree of this action (or the maximum int value if the end of the game tree is reached).
+struct ActionEdge {
+    /// The number of playouts run through this action.
     num_plays: i32,
     /// Proportion of games won after playing this action.
     score: f32,
-    /// Until a playout has been run from the action, this is None.
-    post_state: Option<StateTree>,
+    /// The node of the state the action leads to. Until a playout has been run from the action,
+    /// this is None.
+    child: Option<usize>,
+    /// The number of threads running a playout through this action, in a tree-parallel search.
+    virtual_losses: i32,
 }
 
-/// Game tree starting from a particular state.
+/// A state in the search graph.
 #[derive(Debug)]
-struct StateTree {
+struct StateNode {
     /// The state.
     state: State,
-    /// The number of playouts that have been run from this state.
+    /// The number of playouts that have been run through this state, by any path.
     num_plays: i32,
     /// Actions available.
-    actions: std::collections::HashMap<Action, ActionTree>,
+    actions: ActionMap<ActionEdge>,
+    /// The number of threads running a playout through this state, in a tree-parallel search.
+    virtual_losses: i32,
+}
+
+/// The states found by the search. Orders of moves that lead to the same state (a
+/// transposition) share its node, so its playouts count towards every path to it.
+#[derive(Debug)]
+struct SearchGraph {
+    /// The nodes, with the root first.
+    nodes: Vec<StateNode>,
+    /// The node of each state, by its hash.
+    table: HashMap<u64, usize, BuildHasherDefault<DefaultHasher>>,
+    /// The actions tried from each state.
+    moves: MoveGeneration,
+    /// Roughly how many bytes the nodes take up (see `node_memory`).
+    memory: usize,
+    /// The number of actions, over all the nodes, that don't lead to a node yet. Once there are
+    /// none, every state that can be reached from the root is in the graph.
+    num_untried: usize,
+    /// The number of nodes that threads of a tree-parallel search are making outside the lock,
+    /// which count towards `max_nodes`.
+    num_expanding: usize,
 }
 
-fn choose_random_action(state: &State) -> Option<Action> {
-    let valid_actions = get_valid_actions(&state);
+/// Chooses a random action, using `valid_actions` to list them.
+fn choose_random_action<R: Rng + ?Sized>(
+    state: &State,
+    valid_actions: &mut ActionBuffer,
+    rng: &mut R,
+) -> Option<Action> {
+    fill_valid_actions(state, valid_actions);
     if valid_actions.is_empty() {
         return None;
     }
-    let mut rng = rand::thread_rng();
     let rand_idx = rng.gen_range(0, valid_actions.len());
     Some(valid_actions[rand_idx])
 }
 
-/// Gives each player's probability of winning from the current state.
-/// The state is only provided at the end of each round,
-/// when the centre is empty, floor rows are empty, and full rows
-/// have been emptied.
-
-fn highest_score_action(state_tree: &StateTree) -> Action {
-    let actions = &state_tree.actions;
-    debug_assert!(
-        !actions.is_empty(),
-        format!(
-            "No actions on StateTree for State:\n{}",
-            state_tree.state.to_string()
-        )
-    );
-    let mut best_action = Action {
-        display_number: 0,
-        color: 0,
-        row_id: 0,
+/// Plays random actions until the end of the round, or until `max_depth` actions have been
+/// played, returning the state reached.
+///
+/// The playout runs on a `PackedState`, which is much faster to step, unless the rules have too
+/// long a floor line to pack, in which case the actions are played in place with `apply`. Either
+/// way, the actions are generated without allocating.
+fn run_playout<R: Rng + ?Sized>(state: &State, max_depth: Option<u32>, rng: &mut R) -> State {
+    let mut valid_actions = ActionBuffer::new();
+    let mut depth = 0;
+    let mut packed = match PackedState::from_state(state) {
+        Some(packed) => packed,
+        None => {
+            let mut current_state = state.clone();
+            while max_depth.map_or(true, |max_depth| depth < max_depth) {
+                let action = match choose_random_action(&current_state, &mut valid_actions, rng) {
+                    Some(action) => action,
+                    None => break,
+                };
+                depth += 1;
+                if apply(&mut current_state, action).round_over() {
+                    break;
+                }
+            }
+            return current_state;
+        }
     };
-    let mut best_action_score = -std::f32::MAX;
-    for (action, action_tree) in actions {
-        if action_tree.score > best_action_score {
-            best_action = *action;
-            best_action_score = action_tree.score;
+    while max_depth.map_or(true, |max_depth| depth < max_depth) {
+        depth += 1;
+        packed.fill_valid_actions(&mut valid_actions);
+        if valid_actions.is_empty() {
+            break;
+        }
+        let action = valid_actions[rng.gen_range(0, valid_actions.len())];
+        if packed.step(action, &state.rules) {
+            break;
         }
     }
-    best_action
+    packed.to_state(state.rules.clone())
 }
 
 /// Chooses the action with the maximum MCTS value for further exploration.
 ///
 /// # Returns
 ///
-/// The chosen action, if there is an action for which the game tree hasn't been fully
-/// explored, else None.
-fn choose_mcts_action(state_tree: &StateTree) -> Option<Action> {
-    let log_n = ((1 + state_tree.num_plays) as f32).ln();
+/// The chosen action, or None if there are no actions from the state.
+///
+/// Each playout another thread is running through an action counts as a lost one, so that the
+/// threads of a tree-parallel search spread out over the tree.
+fn choose_mcts_action(node: &StateNode, exploration: f32) -> Option<Action> {
+    let log_n = ((1 + node.num_plays + node.virtual_losses) as f32).ln();
 
-    let (best_action, _) = state_tree
+    let (best_action, _) = node
         .actions
         .iter()
-        .map(|(action, action_tree)| {
+        .map(|(action, action_edge)| {
+            let (score, num_plays) = if action_edge.virtual_losses == 0 {
+                (action_edge.score, action_edge.num_plays)
+            } else {
+                let num_plays = action_edge.num_plays + action_edge.virtual_losses;
+                (
+                    action_edge.score * action_edge.num_plays as f32 / num_plays as f32,
+                    num_plays,
+                )
+            };
             (
                 action,
-                action_tree.score + 1.41 * (log_n / ((1 + action_tree.num_plays) as f32)).sqrt(),
+                score + exploration * (log_n / ((1 + num_plays) as f32)).sqrt(),
             )
         })
         .max_by(|(_, value_a), (_, value_b)| value_a.partial_cmp(value_b).unwrap())?;
     Some(*best_action)
 }
 
-fn create_state_tree(state: State) -> StateTree {
-    let valid_actions = get_valid_actions(&state);
-    let action_trees = valid_actions
+/// Roughly how many bytes a node takes up, with what its state and its actions allocate and its
+/// entry in the table.
+fn node_memory(node: &StateNode) -> usize {
+    let state = &node.state;
+    let state_memory = state.board_states.capacity() * size_of::<PlayerState>()
+        + state
+            .board_states
+            .iter()
+            .map(|board| board.floor.capacity())
+            .sum::<usize>()
+        + state.central_state.central_state_arr.capacity() * size_of::<[u8; 6]>()
+        + state.player_scores.capacity() * size_of::<f32>();
+    // A hash map keeps a control byte for each entry.
+    let actions_memory = node.actions.capacity() * (size_of::<(Action, ActionEdge)>() + 1);
+    let table_memory = size_of::<(u64, usize)>() + 1;
+    size_of::<StateNode>() + state_memory + actions_memory + table_memory
+}
+
+fn create_state_node(state: State, moves: MoveGeneration) -> StateNode {
+    let valid_actions = get_actions(&state, moves);
+    let action_edges: ActionMap<ActionEdge> = valid_actions
         .into_iter()
         .map(|action| (action, Default::default()))
         .collect();
 
-    StateTree {
+    StateNode {
         state,
         num_plays: 0,
-        actions: action_trees,
+        actions: action_edges,
+        virtual_losses: 0,
+    }
+}
+
+impl SearchGraph {
+    fn new(state: State, moves: MoveGeneration) -> SearchGraph {
+        let mut graph = SearchGraph {
+            nodes: Vec::new(),
+            table: Default::default(),
+            moves,
+            memory: 0,
+            num_untried: 0,
+            num_expanding: 0,
+        };
+        graph.add_node(create_state_node(state, moves));
+        graph
+    }
+
+    fn root(&self) -> &StateNode {
+        &self.nodes[0]
+    }
+
+    /// Adds the node of a state that isn't in the graph yet, returning its index.
+    fn add_node(&mut self, node: StateNode) -> usize {
+        let index = self.nodes.len();
+        self.table.insert(node.state.hash, index);
+        self.memory += node_memory(&node);
+        self.num_untried += node.actions.len();
+        self.nodes.push(node);
+        index
+    }
+
+    /// Whether the graph has as many nodes, or takes up as much memory, as the search may use.
+    fn is_full(&self, config: &SearchConfig) -> bool {
+        config
+            .max_nodes
+            .map_or(false, |max| self.nodes.len() + self.num_expanding >= max)
+            || config.max_memory.map_or(false, |max| self.memory >= max)
+    }
+
+    /// Whether the search should stop growing the graph: it's full, or the search is limited
+    /// only by its nodes or memory and the graph holds every state it could add, so that the
+    /// search would never reach the limit.
+    fn is_done(&self, config: &SearchConfig) -> bool {
+        self.is_full(config)
+            || (config.time_limit.is_none() && config.max_playouts.is_none() && self.is_complete())
+    }
+
+    /// Whether every state that can be reached from the root is in the graph.
+    fn is_complete(&self) -> bool {
+        self.num_untried == 0
+    }
+
+    /// Leads an action that hasn't been tried to the node of the state it reaches.
+    fn set_child(&mut self, index: usize, action: Action, child: usize) {
+        let action_edge = self.nodes[index].actions.get_mut(&action).unwrap();
+        if action_edge.child.is_none() {
+            action_edge.child = Some(child);
+            self.num_untried -= 1;
+        }
+    }
+
+    /// The node of a state, if it's in the graph. A state whose hash collides with a different
+    /// state's isn't.
+    fn find_node(&self, state: &State) -> Option<usize> {
+        self.table
+            .get(&state.hash)
+            .cloned()
+            .filter(|index| self.nodes[*index].state == *state)
+    }
+
+    /// The part of the graph that can be reached from a state, with the state at the root, if
+    /// it's in the graph.
+    fn reroot(self, state: &State) -> Option<SearchGraph> {
+        let new_root = self.find_node(state)?;
+        // Number the reachable nodes in the order they're found.
+        let mut new_indices = vec![None; self.nodes.len()];
+        new_indices[new_root] = Some(0);
+        let mut order = vec![new_root];
+        let mut next = 0;
+        while next < order.len() {
+            for action_edge in self.nodes[order[next]].actions.values() {
+                if let Some(child) = action_edge.child {
+                    if new_indices[child].is_none() {
+                        new_indices[child] = Some(order.len());
+                        order.push(child);
+                    }
+                }
+            }
+            next += 1;
+        }
+
+        let mut old_nodes: Vec<_> = self.nodes.into_iter().map(Some).collect();
+        let mut graph = SearchGraph {
+            nodes: Vec::with_capacity(order.len()),
+            table: Default::default(),
+            moves: self.moves,
+            memory: 0,
+            num_untried: 0,
+            num_expanding: 0,
+        };
+        for old_index in order {
+            let mut node = old_nodes[old_index].take().unwrap();
+            for action_edge in node.actions.values_mut() {
+                action_edge.child = action_edge.child.map(|child| new_indices[child].unwrap());
+            }
+            graph.table.insert(node.state.hash, graph.nodes.len());
+            graph.memory += node_memory(&node);
+            graph.num_untried += node
+                .actions
+                .values()
+                .filter(|action_edge| action_edge.child.is_none())
+                .count();
+            graph.nodes.push(node);
+        }
+        Some(graph)
     }
 }
 
-fn mcts_backprop(mut tree: &mut StateTree, actions: &[Action], scores: [f32; 3]) {
-    for action in actions {
-        tree.num_plays += 1;
-        let player_to_play = tree.state.player_to_play;
-        let mut action_tree = tree.actions.get_mut(&action).unwrap();
-        action_tree.score = (action_tree.score * (action_tree.num_plays as f32)
+/// Adds the result of a playout to the statistics of each state and action on the path it took.
+fn mcts_backprop(graph: &mut SearchGraph, path: &[(usize, Action)], scores: &[f32]) {
+    for (index, action) in path {
+        let node = &mut graph.nodes[*index];
+        node.num_plays += 1;
+        let player_to_play = node.state.player_to_play;
+        let action_edge = node.actions.get_mut(action).unwrap();
+        action_edge.score = (action_edge.score * (action_edge.num_plays as f32)
             + scores[player_to_play as usize])
-            / ((action_tree.num_plays + 1) as f32);
-        action_tree.num_plays += 1;
-        tree = action_tree.post_state.as_mut().unwrap();
+            / ((action_edge.num_plays + 1) as f32);
+        action_edge.num_plays += 1;
     }
 }
 
-/// Select, expand and simulate.
-fn mcts_ses<T: ValueFunction>(
-    mut stree: &mut StateTree,
-    vf: &mut T,
-) -> Option<(Vec<Action>, [f32; 3])> {
-    let mut actions = Vec::new();
+/// Selects actions down the graph from a node until one that hasn't been tried or a state with
+/// no actions, adding the node and action at each step to `path`.
+///
+/// Returns the node reached and the action from it that hasn't been tried, if any.
+fn select(
+    graph: &SearchGraph,
+    mut index: usize,
+    exploration: f32,
+    path: &mut Vec<(usize, Action)>,
+) -> (usize, Option<Action>) {
+    while let Some(action) = choose_mcts_action(&graph.nodes[index], exploration) {
+        path.push((index, action));
+        match graph.nodes[index].actions[&action].child {
+            Some(child) => index = child,
+            None => return (index, Some(action)),
+        }
+    }
+    (index, None)
+}
+
+/// Selects actions down the graph until one that hasn't been tried, and adds the state it leads
+/// to. Stops early at a state with no actions.
+///
+/// Returns the path taken, as the node and action at each step, the node reached and whether it
+/// was added.
+fn select_and_expand(
+    graph: &mut SearchGraph,
+    exploration: f32,
+) -> (Vec<(usize, Action)>, usize, bool) {
+    let mut path = Vec::new();
+    let mut index = 0;
 
     loop {
-        match choose_mcts_action(&stree) {
-            Some(action) => {
-                actions.push(action);
-                let action_tree: &mut ActionTree = stree.actions.get_mut(&action).unwrap();
-                if action_tree.post_state.is_some() {
-                    stree = (&mut action_tree.post_state).as_mut().unwrap();
-                } else {
-                    let sstate = stree.state.clone();
-                    let (next_state, _empty_centre) = step(sstate, action, true);
-                    action_tree.post_state = Some(create_state_tree(next_state));
-                    let post_state: &mut Option<StateTree> = &mut action_tree.post_state;
-                    let mut current_state = post_state.as_mut().unwrap().state.clone();
-
-                    // Run the playout.
-                    while let Some(action) = choose_random_action(&current_state) {
-                        let result = step(current_state, action, true);
-                        current_state = result.0;
-                        let empty_centre = result.1;
-                        if empty_centre {
-                            break;
-                        }
-                    }
-                    let scores = vf.get_value(&current_state);
-                    let rval = Some((actions, scores));
-                    return rval;
-                }
+        let (leaf, action) = select(graph, index, exploration, &mut path);
+        let action = match action {
+            Some(action) => action,
+            None => return (path, leaf, false),
+        };
+        // Play the action on the leaf's own state to look the next state up, and take it back
+        // after. The state may have been reached by another order of moves already, in which case
+        // the search carries on from there, and only a new state is copied into its node.
+        let undo = apply(&mut graph.nodes[leaf].state, action);
+        let (child, is_new) = match graph.find_node(&graph.nodes[leaf].state) {
+            Some(child) => {
+                unapply(&mut graph.nodes[leaf].state, undo);
+                (child, false)
             }
             None => {
-                return Some((actions, vf.get_value(&stree.state)));
+                let next_state = graph.nodes[leaf].state.clone();
+                unapply(&mut graph.nodes[leaf].state, undo);
+                (
+                    graph.add_node(create_state_node(next_state, graph.moves)),
+                    true,
+                )
             }
+        };
+        graph.set_child(leaf, action, child);
+        index = child;
+        if is_new {
+            return (path, index, true);
         }
     }
 }
 
+/// Scores a node reached by `select_and_expand`, with a playout if it was just added.
+fn evaluate<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    state: &State,
+    is_new: bool,
+    config: &SearchConfig,
+    vf: &mut T,
+    rng: &mut R,
+) -> Vec<f32> {
+    if is_new {
+        vf.get_value(&run_playout(state, config.rollout_depth, rng))
+    } else {
+        vf.get_value(state)
+    }
+}
+
+/// Select, expand and simulate.
+///
+/// Returns the path taken, as the node and action at each step, and the scores of the playout.
+fn mcts_ses<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    graph: &mut SearchGraph,
+    config: &SearchConfig,
+    vf: &mut T,
+    rng: &mut R,
+) -> Option<(Vec<(usize, Action)>, Vec<f32>)> {
+    let (path, index, is_new) = select_and_expand(graph, config.exploration);
+    let scores = evaluate(&graph.nodes[index].state, is_new, config, vf, rng);
+    Some((path, scores))
+}
+
+/// Counts a playout running through each state and action on a path against them, or stops
+/// counting it with an `amount` of -1.
+fn add_virtual_losses(graph: &mut SearchGraph, path: &[(usize, Action)], amount: i32) {
+    for (index, action) in path {
+        let node = &mut graph.nodes[*index];
+        node.virtual_losses += amount;
+        node.actions.get_mut(action).unwrap().virtual_losses += amount;
+    }
+}
+
 /// Returns whether the tree is complete.
-fn update_tree<T: ValueFunction>(mut state_tree: &mut StateTree, vf: &mut T) -> bool {
-    match mcts_ses(&mut state_tree, vf) {
-        Some((actions, scores)) => {
-            mcts_backprop(state_tree, &actions, scores);
-            assert!(state_tree.num_plays > 0);
+fn update_tree<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    graph: &mut SearchGraph,
+    config: &SearchConfig,
+    vf: &mut T,
+    rng: &mut R,
+) -> bool {
+    match mcts_ses(graph, config, vf, rng) {
+        Some((path, scores)) => {
+            mcts_backprop(graph, &path, &scores);
             false
         }
         None => {
@@ -169,34 +435,921 @@ fn update_tree<T: ValueFunction>(mut state_tree: &mut StateTree, vf: &mut T) ->
         }
     }
 }
+
+/// What a search did.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SearchStats {
+    /// The number of playouts run.
+    pub num_playouts: i32,
+    /// The number of playouts kept from the previous search by a `Searcher`, which aren't
+    /// counted in `num_playouts`.
+    pub retained_playouts: i32,
+    /// The number of states in the tree at the end of the search, over all its threads' trees.
+    pub num_nodes: usize,
+    /// Roughly how many bytes the tree took up at the end of the search.
+    pub memory: usize,
+    /// Whether the tree held every state that can be reached from the root at the end of the
+    /// search.
+    pub is_complete: bool,
+    /// The statistics of each action from the root, added up over the threads' trees in a
+    /// root-parallel search.
+    pub root_actions: Vec<ActionStats>,
+}
+
+/// The statistics of an action from the root of a search.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ActionStats {
+    pub action: Action,
+    /// The number of playouts run through the action.
+    pub num_plays: i32,
+    /// The mean score of the player to play over those playouts.
+    pub score: f32,
+}
+
+/// How the action to play is chosen once the search is over.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FinalMovePolicy {
+    /// The action with the highest mean score among those with at least `min_playouts`
+    /// playouts (the max child), or the robust child if none has that many. With few playouts,
+    /// the highest mean can be luck.
+    MaxChild { min_playouts: i32 },
+    /// The action with the most playouts (the robust child), which the search tried most because
+    /// it kept looking best. Ties go to the higher mean score.
+    RobustChild,
+    /// The action with the highest lower confidence bound on its score (the secure child): its
+    /// mean score less `confidence * sqrt(ln(total playouts) / playouts)`.
+    SecureChild { confidence: f32 },
+}
+
+impl FromStr for FinalMovePolicy {
+    type Err = String;
+
+    /// Parses `max`, `max:<min playouts>`, `robust`, `secure` or `secure:<confidence>`.
+    fn from_str(policy: &str) -> Result<FinalMovePolicy, String> {
+        let mut parts = policy.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let parameter = parts.next();
+        let invalid = || format!("Invalid final move policy: {}", policy);
+        match (name, parameter) {
+            ("max", None) => Ok(FinalMovePolicy::MaxChild { min_playouts: 0 }),
+            ("max", Some(min_playouts)) => Ok(FinalMovePolicy::MaxChild {
+                min_playouts: min_playouts.parse().map_err(|_| invalid())?,
+            }),
+            ("robust", None) => Ok(FinalMovePolicy::RobustChild),
+            ("secure", None) => Ok(FinalMovePolicy::SecureChild { confidence: 1. }),
+            ("secure", Some(confidence)) => Ok(FinalMovePolicy::SecureChild {
+                confidence: confidence.parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Makes a value function for each thread of a parallel search, or fails to load one.
+pub type ValueFunctionFactory =
+    dyn Fn() -> Result<Box<dyn ValueFunction + Send>, GameError> + Send + Sync;
+
+/// How a search shares the work between threads.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Parallelism {
+    /// Each thread searches a tree of its own, and the statistics of the actions from the root
+    /// are added up at the end.
+    Root,
+    /// The threads search the same tree. A thread running a playout counts as a loss for the
+    /// actions on its path until it's done (a virtual loss), steering the others elsewhere.
+    Tree,
+}
+
+/// How to search.
+#[derive(Clone)]
+pub struct SearchConfig {
+    /// The exploration constant of UCT: how much the search favours actions with few playouts
+    /// over actions with high scores.
+    pub exploration: f32,
+    /// How long to search for, if limited.
+    pub time_limit: Option<Duration>,
+    /// The number of playouts to run, if limited. The search stops at whichever limit it
+    /// reaches first, and needs at least one.
+    pub max_playouts: Option<i32>,
+    /// The number of states the tree may hold, if limited, counting those kept from the last
+    /// search by a `Searcher`.
+    pub max_nodes: Option<usize>,
+    /// Roughly how many bytes the tree may take up, if limited. Like `max_nodes`, it includes
+    /// the part of the tree kept from the last search.
+    ///
+    /// A search limited only by its nodes or memory also stops once the tree holds every state
+    /// that can be reached, as it would never reach the limit. UCT may take many playouts to try
+    /// the last actions of a small tree, so a time or playout limit bounds the search better.
+    pub max_memory: Option<usize>,
+    /// The number of threads to search on, including the calling one.
+    pub num_threads: usize,
+    pub parallelism: Parallelism,
+    /// Makes the value functions for the threads other than the calling one, which uses the one
+    /// passed to `search`. A `Searcher` makes them once, when it's made. Needed for more than
+    /// one thread.
+    pub make_vf: Option<Arc<ValueFunctionFactory>>,
+    /// The actions tried from each state in the tree. The playouts choose from all the actions.
+    pub moves: MoveGeneration,
+    pub final_move: FinalMovePolicy,
+    /// The most random actions a playout plays before the value function judges the state it
+    /// reached. Unlimited playouts stop at the end of the round.
+    pub rollout_depth: Option<u32>,
+    /// Print the playouts and scores of the actions from the root to stderr after searching.
+    pub verbose: bool,
+}
+
+impl Default for SearchConfig {
+    /// Searches for 0.4 seconds on one thread.
+    fn default() -> SearchConfig {
+        SearchConfig {
+            exploration: 1.41,
+            time_limit: Some(Duration::from_millis(400)),
+            max_playouts: None,
+            max_nodes: None,
+            max_memory: None,
+            num_threads: 1,
+            parallelism: Parallelism::Tree,
+            make_vf: None,
+            moves: MoveGeneration::All,
+            final_move: FinalMovePolicy::MaxChild { min_playouts: 0 },
+            rollout_depth: None,
+            verbose: false,
+        }
+    }
+}
+
+impl fmt::Debug for SearchConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SearchConfig")
+            .field("exploration", &self.exploration)
+            .field("time_limit", &self.time_limit)
+            .field("max_playouts", &self.max_playouts)
+            .field("max_nodes", &self.max_nodes)
+            .field("max_memory", &self.max_memory)
+            .field("num_threads", &self.num_threads)
+            .field("parallelism", &self.parallelism)
+            .field("make_vf", &self.make_vf.as_ref().map(|_| "..."))
+            .field("moves", &self.moves)
+            .field("final_move", &self.final_move)
+            .field("rollout_depth", &self.rollout_depth)
+            .field("verbose", &self.verbose)
+            .finish()
+    }
+}
+
+fn root_action_stats(graph: &SearchGraph) -> Vec<ActionStats> {
+    graph
+        .root()
+        .actions
+        .iter()
+        .map(|(action, edge)| ActionStats {
+            action: *action,
+            num_plays: edge.num_plays,
+            score: edge.score,
+        })
+        .collect()
+}
+
+/// The first action with the highest value.
+fn max_by_value<F: Fn(&ActionStats) -> f32>(
+    root_actions: &[ActionStats],
+    value: F,
+) -> Option<&ActionStats> {
+    let mut best: Option<(&ActionStats, f32)> = None;
+    for action_stats in root_actions {
+        let action_value = value(action_stats);
+        if best.map_or(true, |(_, best_value)| action_value > best_value) {
+            best = Some((action_stats, action_value));
+        }
+    }
+    best.map(|(action_stats, _)| action_stats)
+}
+
+/// Chooses the action to play from the statistics of the actions from the root of a search,
+/// such as `SearchStats::root_actions`, or None if there are no actions.
+pub fn choose_action(root_actions: &[ActionStats], policy: FinalMovePolicy) -> Option<Action> {
+    let best = match policy {
+        FinalMovePolicy::MaxChild { min_playouts } => {
+            let has_enough_playouts =
+                |action_stats: &ActionStats| action_stats.num_plays >= min_playouts;
+            if !root_actions.iter().any(has_enough_playouts) {
+                return choose_action(root_actions, FinalMovePolicy::RobustChild);
+            }
+            max_by_value(root_actions, |action_stats| {
+                if has_enough_playouts(action_stats) {
+                    action_stats.score
+                } else {
+                    std::f32::NEG_INFINITY
+                }
+            })
+        }
+        FinalMovePolicy::RobustChild => {
+            let most_plays = root_actions
+                .iter()
+                .map(|action_stats| action_stats.num_plays)
+                .max()?;
+            max_by_value(root_actions, |action_stats| {
+                if action_stats.num_plays == most_plays {
+                    action_stats.score
+                } else {
+                    std::f32::NEG_INFINITY
+                }
+            })
+        }
+        FinalMovePolicy::SecureChild { confidence } => {
+            let total_plays: i32 = root_actions
+                .iter()
+                .map(|action_stats| action_stats.num_plays)
+                .sum();
+            let log_n = (total_plays.max(1) as f32).ln();
+            max_by_value(root_actions, |action_stats| {
+                if action_stats.num_plays == 0 {
+                    std::f32::NEG_INFINITY
+                } else {
+                    action_stats.score - confidence * (log_n / action_stats.num_plays as f32).sqrt()
+                }
+            })
+        }
+    };
+    best.map(|action_stats| action_stats.action)
+}
+
+fn print_root_stats(stats: &SearchStats, start: SystemTime) {
+    let elapsed = start.elapsed().unwrap_or_default();
+    eprintln!(
+        "{} playouts in {:.2}s, {} kept from the last search. {} nodes, {} kB.",
+        stats.num_playouts,
+        elapsed.as_secs_f32(),
+        stats.retained_playouts,
+        stats.num_nodes,
+        stats.memory / 1024
+    );
+    let mut root_stats = stats.root_actions.clone();
+    root_stats.sort_by_key(|action_stats| std::cmp::Reverse(action_stats.num_plays));
+    for action_stats in root_stats {
+        eprintln!(
+            "  {}: {} playouts, score {:.3}",
+            action_stats.action, action_stats.num_plays, action_stats.score
+        );
+    }
+}
+
+/// Chooses an action with `search`.
+pub fn make_move<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    state: &State,
+    config: &SearchConfig,
+    vf: &mut T,
+    rng: &mut R,
+) -> Result<Action, GameError> {
+    Ok(search(state, config, vf, rng)?.0)
+}
+
 /// 1) Run playouts until:
-///    a) the time limit expires; or
-///    b) the full game tree has been explored.
-/// 2) Return the action with the highest score.
-pub fn make_move<T: ValueFunction>(
+///    a) the time limit expires;
+///    b) the maximum number of playouts has been run;
+///    c) the tree holds the maximum number of nodes or takes up the maximum memory; or
+///    d) the full game tree has been explored, if the search is limited only by c).
+/// 2) Return the action chosen by the final move policy.
+///
+/// All random choices are made with `rng`, so without a time limit the same seed always gives
+/// the same action, unless the search is tree-parallel, which depends on how
+/// the threads are scheduled.
+///
+/// The value functions of the threads other than the calling one are made with
+/// `config.make_vf` for this search alone, and fail it if they can't be made; a `Searcher`
+/// keeps them from one search to the next.
+pub fn search<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
     state: &State,
-    _time_limit: std::time::Duration,
+    config: &SearchConfig,
     vf: &mut T,
-) -> Action {
-    let start = std::time::SystemTime::now();
-    let mut state_tree = create_state_tree(state.clone());
-    let mut is_complete = false;
-    while std::time::SystemTime::now() < start + _time_limit {
-        if update_tree(&mut state_tree, vf) {
-            is_complete = true;
-            break;
+    rng: &mut R,
+) -> Result<(Action, SearchStats), GameError> {
+    let mut worker_vfs = make_worker_vfs(config)?;
+    let graph = SearchGraph::new(state.clone(), config.moves);
+    let (_, action, stats) = search_graph(graph, config, vf, &mut worker_vfs, rng);
+    Ok((action, stats))
+}
+
+/// Makes the value functions of the threads other than the calling one.
+fn make_worker_vfs(config: &SearchConfig) -> Result<Vec<Box<dyn ValueFunction + Send>>, GameError> {
+    if config.num_threads <= 1 {
+        return Ok(Vec::new());
+    }
+    let make_vf = config.make_vf.as_ref().ok_or_else(|| {
+        GameError::InvalidConfig("a search on several threads needs make_vf".to_string())
+    })?;
+    (1..config.num_threads).map(|_| make_vf()).collect()
+}
+
+/// Searches from one state after another, keeping the tree between searches. When a state was
+/// reached in the last search, as the states after the players' moves usually are, the search
+/// carries on from its node with the playouts already run through it. The tree is discarded
+/// when the state isn't in it, which it never is after the factory displays have been refilled.
+///
+/// A root-parallel search keeps only the tree of the calling thread.
+///
+/// The searcher also keeps the value functions of the threads other than the calling one, so
+/// that they're only made once.
+#[derive(Default)]
+pub struct Searcher {
+    graph: Option<SearchGraph>,
+    worker_vfs: Vec<Box<dyn ValueFunction + Send>>,
+}
+
+impl Searcher {
+    /// Makes a searcher for `config.num_threads` threads, with `config.make_vf` making the value
+    /// functions of all but the calling one.
+    pub fn new(config: &SearchConfig) -> Result<Searcher, GameError> {
+        Ok(Searcher {
+            graph: None,
+            worker_vfs: make_worker_vfs(config)?,
+        })
+    }
+
+    /// Like `search`, from the part of the last search's tree that can be reached from `state`.
+    /// `config` must ask for no more threads than the one the searcher was made with.
+    pub fn search<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+        &mut self,
+        state: &State,
+        config: &SearchConfig,
+        vf: &mut T,
+        rng: &mut R,
+    ) -> (Action, SearchStats) {
+        let graph = self
+            .graph
+            .take()
+            .filter(|graph| graph.moves == config.moves)
+            .and_then(|graph| graph.reroot(state))
+            .unwrap_or_else(|| SearchGraph::new(state.clone(), config.moves));
+        let (graph, action, stats) = search_graph(graph, config, vf, &mut self.worker_vfs, rng);
+        self.graph = Some(graph);
+        (action, stats)
+    }
+
+    /// Discards the tree.
+    pub fn clear(&mut self) {
+        self.graph = None;
+    }
+}
+
+impl fmt::Debug for Searcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Searcher")
+            .field("graph", &self.graph)
+            .field(
+                "worker_vfs",
+                &format!("{} value functions", self.worker_vfs.len()),
+            )
+            .finish()
+    }
+}
+
+/// Searches from the root of a graph, returning the graph grown by the search. The threads other
+/// than the calling one use `worker_vfs`.
+fn search_graph<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    mut graph: SearchGraph,
+    config: &SearchConfig,
+    vf: &mut T,
+    worker_vfs: &mut Vec<Box<dyn ValueFunction + Send>>,
+    rng: &mut R,
+) -> (SearchGraph, Action, SearchStats) {
+    assert!(
+        config.time_limit.is_some()
+            || config.max_playouts.is_some()
+            || config.max_nodes.is_some()
+            || config.max_memory.is_some(),
+        "The search needs a limit on its time, playouts, nodes or memory."
+    );
+    assert!(
+        config.num_threads <= 1 + worker_vfs.len(),
+        "The Searcher was made for fewer threads than the search asks for."
+    );
+    let start = SystemTime::now();
+    let deadline = config.time_limit.map(|limit| start + limit);
+    let retained_playouts = graph.root().num_plays;
+    let (root_stats, mut stats) = if config.num_threads <= 1 {
+        let stats = grow_graph(&mut graph, deadline, config, vf, rng);
+        (root_action_stats(&graph), stats)
+    } else {
+        match config.parallelism {
+            Parallelism::Root => {
+                root_parallel_search(&mut graph, deadline, config, vf, worker_vfs, rng)
+            }
+            Parallelism::Tree => {
+                let (tree_graph, root_stats, stats) =
+                    tree_parallel_search(graph, deadline, config, vf, worker_vfs, rng);
+                graph = tree_graph;
+                (root_stats, stats)
+            }
         }
+    };
+    stats.retained_playouts = retained_playouts;
+    stats.root_actions = root_stats;
+    if config.verbose {
+        print_root_stats(&stats, start);
     }
-    let dur = time::Duration::from_millis(100);
-    thread::sleep(dur);
-    println!(
-        "{} playouts{}.",
-        state_tree.num_plays,
-        if is_complete {
-            " (tree fully explored)"
-        } else {
-            ""
+    let action = choose_action(&stats.root_actions, config.final_move)
+        .expect("There are no actions to choose from.");
+    (graph, action, stats)
+}
+
+/// Runs playouts from the root of a graph until the deadline, the maximum number of playouts or
+/// the graph is full.
+fn grow_graph<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    graph: &mut SearchGraph,
+    deadline: Option<SystemTime>,
+    config: &SearchConfig,
+    vf: &mut T,
+    rng: &mut R,
+) -> SearchStats {
+    let mut num_playouts = 0;
+    while deadline.map_or(true, |deadline| SystemTime::now() < deadline)
+        && config.max_playouts.map_or(true, |max| num_playouts < max)
+        && !graph.is_done(config)
+    {
+        update_tree(graph, config, vf, rng);
+        num_playouts += 1;
+    }
+    SearchStats {
+        num_playouts,
+        retained_playouts: 0,
+        num_nodes: graph.nodes.len(),
+        memory: graph.memory,
+        is_complete: graph.is_complete(),
+        root_actions: Vec::new(),
+    }
+}
+
+/// Searches a tree on each thread and adds up the statistics of the actions from the root. The
+/// calling thread grows `graph`, and the others start trees of their own.
+fn root_parallel_search<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    graph: &mut SearchGraph,
+    deadline: Option<SystemTime>,
+    config: &SearchConfig,
+    vf: &mut T,
+    worker_vfs: &mut Vec<Box<dyn ValueFunction + Send>>,
+    rng: &mut R,
+) -> (Vec<ActionStats>, SearchStats) {
+    let num_threads = config.num_threads;
+    // Share the budgets out between the threads.
+    let share = |budget: usize, thread_num: usize| {
+        budget / num_threads
+            + if thread_num < budget % num_threads {
+                1
+            } else {
+                0
+            }
+    };
+    let thread_config = |thread_num: usize| SearchConfig {
+        max_playouts: config
+            .max_playouts
+            .map(|max| share(max as usize, thread_num) as i32),
+        max_nodes: config.max_nodes.map(|max| share(max, thread_num)),
+        max_memory: config.max_memory.map(|max| share(max, thread_num)),
+        ..config.clone()
+    };
+    // The value functions go to the threads and come back when they're done.
+    let handles: Vec<_> = (1..num_threads)
+        .zip(worker_vfs.drain(..num_threads - 1))
+        .map(|(thread_num, mut vf)| {
+            let state = graph.root().state.clone();
+            let config = thread_config(thread_num);
+            let seed = rng.gen();
+            std::thread::spawn(move || {
+                let mut rng = SmallRng::seed_from_u64(seed);
+                let mut graph = SearchGraph::new(state, config.moves);
+                let stats = grow_graph(&mut graph, deadline, &config, &mut *vf, &mut rng);
+                (root_action_stats(&graph), stats, vf)
+            })
+        })
+        .collect();
+    let mut stats = grow_graph(graph, deadline, &thread_config(0), vf, rng);
+    let mut root_stats = root_action_stats(graph);
+
+    for handle in handles {
+        let (thread_root_stats, thread_stats, thread_vf) =
+            handle.join().expect("A search thread panicked.");
+        worker_vfs.push(thread_vf);
+        add_root_stats(&mut root_stats, thread_root_stats);
+        stats.num_playouts += thread_stats.num_playouts;
+        stats.num_nodes += thread_stats.num_nodes;
+        stats.memory += thread_stats.memory;
+        stats.is_complete &= thread_stats.is_complete;
+    }
+    (root_stats, stats)
+}
+
+/// Adds the statistics of the actions from another tree's root to those from this one's. The
+/// roots list their actions in whatever order their tables hold them, so they're matched up by
+/// action.
+fn add_root_stats(root_stats: &mut Vec<ActionStats>, other_root_stats: Vec<ActionStats>) {
+    for action_stats in other_root_stats {
+        match root_stats
+            .iter_mut()
+            .find(|total| total.action == action_stats.action)
+        {
+            Some(total) => {
+                let num_plays = total.num_plays + action_stats.num_plays;
+                if num_plays > 0 {
+                    total.score = (total.score * total.num_plays as f32
+                        + action_stats.score * action_stats.num_plays as f32)
+                        / num_plays as f32;
+                }
+                total.num_plays = num_plays;
+            }
+            None => root_stats.push(action_stats),
         }
-    );
-    highest_score_action(&state_tree)
+    }
+}
+
+/// Runs playouts in a tree shared with other threads until the deadline, the maximum number of
+/// playouts between them or the tree is full, returning the number this thread ran.
+///
+/// Only the selection and the updates hold the lock. The thread steps to the state it expands,
+/// lists its actions and runs the playout from it on its own, and another thread may add the same
+/// state meanwhile, in which case the playout counts towards the existing node.
+fn run_tree_worker<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    graph: &Mutex<SearchGraph>,
+    num_started: &AtomicI32,
+    deadline: Option<SystemTime>,
+    config: &SearchConfig,
+    vf: &mut T,
+    rng: &mut R,
+) -> i32 {
+    let mut num_playouts = 0;
+    let mut path = Vec::new();
+    while deadline.map_or(true, |deadline| SystemTime::now() < deadline)
+        && config
+            .max_playouts
+            .map_or(true, |max| num_started.fetch_add(1, Ordering::SeqCst) < max)
+    {
+        path.clear();
+        let (leaf, action, state) = {
+            let mut graph = graph.lock().unwrap();
+            if graph.is_done(config) {
+                break;
+            }
+            let (leaf, action) = select(&graph, 0, config.exploration, &mut path);
+            add_virtual_losses(&mut graph, &path, 1);
+            if action.is_some() {
+                graph.num_expanding += 1;
+            }
+            // The thread steps outside the lock, so it needs its own copy, which becomes the
+            // state of the node it adds.
+            (leaf, action, graph.nodes[leaf].state.clone())
+        };
+        let (expanded, scores) = match action {
+            Some(action) => {
+                let (next_state, _empty_centre) = step(state, action, true);
+                let scores = evaluate(&next_state, true, config, vf, rng);
+                (
+                    Some((action, create_state_node(next_state, config.moves))),
+                    scores,
+                )
+            }
+            None => (None, evaluate(&state, false, config, vf, rng)),
+        };
+        let mut graph = graph.lock().unwrap();
+        if let Some((action, node)) = expanded {
+            graph.num_expanding -= 1;
+            let child = match graph.find_node(&node.state) {
+                Some(child) => child,
+                None => graph.add_node(node),
+            };
+            graph.set_child(leaf, action, child);
+        }
+        add_virtual_losses(&mut graph, &path, -1);
+        mcts_backprop(&mut graph, &path, &scores);
+        num_playouts += 1;
+    }
+    num_playouts
+}
+
+/// Searches one tree on all the threads, returning the grown tree.
+fn tree_parallel_search<T: ValueFunction + ?Sized, R: Rng + ?Sized>(
+    graph: SearchGraph,
+    deadline: Option<SystemTime>,
+    config: &SearchConfig,
+    vf: &mut T,
+    worker_vfs: &mut Vec<Box<dyn ValueFunction + Send>>,
+    rng: &mut R,
+) -> (SearchGraph, Vec<ActionStats>, SearchStats) {
+    let graph = Arc::new(Mutex::new(graph));
+    // The number of playouts started, so that the threads stop at the maximum between them.
+    let num_started = Arc::new(AtomicI32::new(0));
+    let handles: Vec<_> = worker_vfs
+        .drain(..config.num_threads - 1)
+        .map(|mut vf| {
+            let graph = graph.clone();
+            let num_started = num_started.clone();
+            let config = config.clone();
+            let seed = rng.gen();
+            std::thread::spawn(move || {
+                let mut rng = SmallRng::seed_from_u64(seed);
+                let num_playouts =
+                    run_tree_worker(&graph, &num_started, deadline, &config, &mut *vf, &mut rng);
+                (num_playouts, vf)
+            })
+        })
+        .collect();
+    let mut num_playouts = run_tree_worker(&graph, &num_started, deadline, config, vf, rng);
+    for handle in handles {
+        let (thread_playouts, thread_vf) = handle.join().expect("A search thread panicked.");
+        num_playouts += thread_playouts;
+        worker_vfs.push(thread_vf);
+    }
+    // All the other threads are done with the graph.
+    let graph = match Arc::try_unwrap(graph) {
+        Ok(graph) => graph.into_inner().unwrap(),
+        Err(_) => unreachable!(),
+    };
+    let root_stats = root_action_stats(&graph);
+    let stats = SearchStats {
+        num_playouts,
+        retained_playouts: 0,
+        num_nodes: graph.nodes.len(),
+        memory: graph.memory,
+        is_complete: graph.is_complete(),
+        root_actions: Vec::new(),
+    };
+    (graph, root_stats, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::RuleSet;
+
+    fn opening_state(seed: u64) -> State {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        get_random_initial_state(3, Arc::new(RuleSet::default()), &mut rng)
+    }
+
+    /// A state near the end of the first round, whose whole game tree is small.
+    fn end_of_round_state(seed: u64) -> State {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut state = get_random_initial_state(3, Arc::new(RuleSet::default()), &mut rng);
+        while count_legal_actions(&state) > 4 {
+            let valid_actions = get_valid_actions(&state);
+            let action = valid_actions[rng.gen_range(0, valid_actions.len())];
+            state = step(state, action, true).0;
+        }
+        state
+    }
+
+    /// A config without a time limit.
+    fn budget_config() -> SearchConfig {
+        SearchConfig {
+            time_limit: None,
+            ..SearchConfig::default()
+        }
+    }
+
+    fn search_with_seed(state: &State, config: &SearchConfig, seed: u64) -> (Action, SearchStats) {
+        let make_vf: Arc<ValueFunctionFactory> =
+            Arc::new(|| Ok(Box::new(ValueFunctionScore::new())));
+        let config = SearchConfig {
+            make_vf: Some(make_vf),
+            ..config.clone()
+        };
+        let mut searcher = Searcher::new(&config).unwrap();
+        let mut vf = ValueFunctionScore::new();
+        let mut rng = SmallRng::seed_from_u64(seed);
+        searcher.search(state, &config, &mut vf, &mut rng)
+    }
+
+    #[test]
+    fn fixed_budget_searches_are_reproducible() {
+        let budgets = vec![
+            SearchConfig {
+                max_playouts: Some(500),
+                ..budget_config()
+            },
+            SearchConfig {
+                max_nodes: Some(300),
+                ..budget_config()
+            },
+            SearchConfig {
+                max_memory: Some(1 << 18),
+                ..budget_config()
+            },
+            SearchConfig {
+                max_playouts: Some(500),
+                num_threads: 2,
+                parallelism: Parallelism::Root,
+                ..budget_config()
+            },
+        ];
+        for config in budgets {
+            for state in &[opening_state(1), end_of_round_state(2)] {
+                let first = search_with_seed(state, &config, 3);
+                assert_eq!(search_with_seed(state, &config, 3), first, "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn search_makes_its_threads_value_functions() {
+        let state = opening_state(1);
+        let make_vf: Arc<ValueFunctionFactory> =
+            Arc::new(|| Ok(Box::new(ValueFunctionScore::new())));
+        let config = SearchConfig {
+            max_playouts: Some(200),
+            num_threads: 2,
+            parallelism: Parallelism::Root,
+            make_vf: Some(make_vf),
+            ..budget_config()
+        };
+        let mut vf = ValueFunctionScore::new();
+        let mut rng = SmallRng::seed_from_u64(3);
+        let (_, stats) = search(&state, &config, &mut vf, &mut rng).unwrap();
+        assert_eq!(stats.num_playouts, 200);
+
+        let failing: Arc<ValueFunctionFactory> =
+            Arc::new(|| Err(GameError::ModelLoad("missing".to_string())));
+        let config = SearchConfig {
+            make_vf: Some(failing),
+            ..config
+        };
+        assert_eq!(
+            search(&state, &config, &mut vf, &mut rng).err(),
+            Some(GameError::ModelLoad("missing".to_string()))
+        );
+        let config = SearchConfig {
+            make_vf: None,
+            ..config
+        };
+        assert!(make_move(&state, &config, &mut vf, &mut rng).is_err());
+    }
+
+    #[test]
+    fn max_nodes_stops_the_search() {
+        let config = SearchConfig {
+            max_nodes: Some(300),
+            ..budget_config()
+        };
+        let (_, stats) = search_with_seed(&opening_state(1), &config, 3);
+        assert_eq!(stats.num_nodes, 300);
+        assert!(!stats.is_complete);
+
+        for parallelism in &[Parallelism::Root, Parallelism::Tree] {
+            let config = SearchConfig {
+                num_threads: 3,
+                parallelism: *parallelism,
+                ..config.clone()
+            };
+            let (_, stats) = search_with_seed(&opening_state(1), &config, 3);
+            assert!(stats.num_nodes <= 300, "{:?}", parallelism);
+        }
+    }
+
+    #[test]
+    fn max_memory_stops_the_search() {
+        let max_memory = 1 << 18;
+        let config = SearchConfig {
+            max_memory: Some(max_memory),
+            ..budget_config()
+        };
+        let graph = SearchGraph::new(opening_state(1), config.moves);
+        let mut vf = ValueFunctionScore::new();
+        let mut rng = SmallRng::seed_from_u64(3);
+        let (graph, _, stats) = search_graph(graph, &config, &mut vf, &mut Vec::new(), &mut rng);
+        assert!(!stats.is_complete);
+        assert!(graph.memory >= max_memory);
+        // The search stopped as soon as the last node took it over the limit.
+        assert!(graph.memory - node_memory(graph.nodes.last().unwrap()) < max_memory);
+    }
+
+    #[test]
+    fn node_budgets_stop_in_small_trees() {
+        let config = SearchConfig {
+            max_nodes: Some(1_000_000),
+            ..budget_config()
+        };
+        for seed in 0..5 {
+            let state = end_of_round_state(seed);
+            let (action, stats) = search_with_seed(&state, &config, 3);
+            assert!(stats.num_nodes < 1_000_000);
+            assert!(is_valid_action(&state, action));
+        }
+    }
+
+    #[test]
+    fn playout_budgets_run_past_a_complete_tree() {
+        let config = SearchConfig {
+            max_playouts: Some(500),
+            ..budget_config()
+        };
+        let (_, stats) = search_with_seed(&end_of_round_state(2), &config, 3);
+        assert!(stats.is_complete);
+        assert_eq!(stats.num_playouts, 500);
+    }
+
+    #[test]
+    fn searcher_keeps_the_tree() {
+        let state = opening_state(1);
+        let config = SearchConfig {
+            max_playouts: Some(500),
+            ..budget_config()
+        };
+        let mut searcher = Searcher::new(&config).unwrap();
+        let mut vf = ValueFunctionScore::new();
+        let mut rng = SmallRng::seed_from_u64(3);
+        let (action, _) = searcher.search(&state, &config, &mut vf, &mut rng);
+        let (next_state, _) = step(state, action, true);
+        let (_, stats) = searcher.search(&next_state, &config, &mut vf, &mut rng);
+        assert!(stats.retained_playouts > 0);
+        assert_eq!(stats.num_playouts, 500);
+    }
+
+    /// The statistics of taking blue tiles from a display to the first row.
+    fn take_stats(display_number: u8, num_plays: i32, score: f32) -> ActionStats {
+        ActionStats {
+            action: Action::Take {
+                display_number,
+                color: 0,
+                row_id: 0,
+            },
+            num_plays,
+            score,
+        }
+    }
+
+    #[test]
+    fn root_stats_are_added_up_by_action() {
+        let mut root_stats = vec![
+            take_stats(0, 1, 1.),
+            take_stats(1, 3, 0.),
+            take_stats(2, 0, 0.),
+        ];
+        add_root_stats(
+            &mut root_stats,
+            vec![
+                take_stats(3, 2, 1.),
+                take_stats(2, 0, 0.),
+                take_stats(1, 1, 4.),
+                take_stats(0, 1, 0.),
+            ],
+        );
+        assert_eq!(
+            root_stats,
+            vec![
+                take_stats(0, 2, 0.5),
+                take_stats(1, 4, 1.),
+                take_stats(2, 0, 0.),
+                take_stats(3, 2, 1.)
+            ]
+        );
+    }
+
+    #[test]
+    fn final_move_policies() {
+        // Display 0 has the best mean over a few playouts, display 1 the most playouts and
+        // display 2 the best mean once the number of playouts is allowed for.
+        let root_stats = [
+            take_stats(0, 2, 0.9),
+            take_stats(1, 100, 0.5),
+            take_stats(2, 60, 0.6),
+            take_stats(3, 0, 0.),
+        ];
+        let choice = |policy: &str| {
+            choose_action(&root_stats, policy.parse().unwrap()).map(|action| match action {
+                Action::Take { display_number, .. } => display_number,
+                Action::Place { .. } => panic!("not a take"),
+            })
+        };
+        assert_eq!(choice("max"), Some(0));
+        assert_eq!(choice("robust"), Some(1));
+        assert_eq!(choice("secure"), Some(2));
+        // Only displays 1 and 2 have enough playouts.
+        assert_eq!(choice("max:10"), Some(2));
+        // None has enough, so the most played action is chosen.
+        assert_eq!(choice("max:1000"), Some(1));
+        // With little confidence needed, the highest mean wins.
+        assert_eq!(choice("secure:0.01"), Some(0));
+        assert_eq!(choose_action(&[], FinalMovePolicy::RobustChild), None);
+    }
+
+    #[test]
+    fn parse_final_move_policies() {
+        assert_eq!(
+            "max:10".parse(),
+            Ok(FinalMovePolicy::MaxChild { min_playouts: 10 })
+        );
+        assert_eq!(
+            "secure:2.5".parse(),
+            Ok(FinalMovePolicy::SecureChild { confidence: 2.5 })
+        );
+        for policy in [
+            "", "min", "max:", "max:x", "max:1.5", "robust:1", "secure:", "secure:x",
+        ]
+        .iter()
+        {
+            assert!(
+                policy.parse::<FinalMovePolicy>().is_err(),
+                "accepted {}",
+                policy
+            );
+        }
+    }
 }
@@ -0,0 +1,587 @@
+//! Compact notation for actions and positions.
+//!
+//! A `Take` action is written as the display (`0`-`8`, or `C` for the centre), the color
+//! (`B`, `Y`, `R`, `G` or `C`) and the destination (row `0`-`4`, or `F` for the floor), e.g. `3R2`
+//! or `CBF`. A `Place` action is written as `W`, the row and the column, e.g. `W24`. Whitespace is
+//! ignored when parsing and letters may be lower case.
+//!
+//! A position is a single line of space-separated fields:
+//!
+//! 1. The players' boards, separated by `/`. Each board is the wall, pattern lines, floor and
+//!    score, separated by `:`. The wall is five rows separated by `,`, each written like a chess
+//!    FEN rank: a color letter for each tile and a digit for each run of empty spaces, e.g. `B3R`.
+//!    The pattern lines are five entries separated by `,`, each `-` if empty or the count and
+//!    color, e.g. `2R`. The floor lists the tiles on it in order (`F` for the first player
+//!    token), or `-`.
+//! 2. The factory displays, separated by `,`, each listing its tiles or `-` if empty.
+//! 3. The tiles in the centre (`F` for the first player token), or `-`.
+//! 4. The numbers of tiles of each color in the bag, separated by `,`.
+//! 5. The same for the lid.
+//! 6. The player to play, followed by `t` if the players are moving complete rows to a free wall,
+//!    or by `-` if the game is finished.
+//!
+//! For example, the start of a 2-player game:
+//!
+//! `5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0,0,0,0,0 0`
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::game_state::*;
+use crate::rules::RuleSet;
+use crate::zobrist::compute_hash;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseActionError {
+    notation: String,
+}
+
+impl Display for ParseActionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\" is not a move: expected e.g. 3R2 (display 3, red, row 2) or CBF (centre, blue, floor)",
+            self.notation
+        )
+    }
+}
+
+impl std::error::Error for ParseActionError {}
+
+fn color_from_char(c: char) -> Option<u8> {
+    COLOR_NAMES[..5]
+        .iter()
+        .position(|name| *name == c)
+        .map(|color| color as u8)
+}
+
+fn digit_from_char(c: char, max: u8) -> Option<u8> {
+    c.to_digit(10)
+        .map(|digit| digit as u8)
+        .filter(|digit| *digit <= max)
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Take {
+                display_number,
+                color,
+                row_id,
+            } => {
+                if display_number == CENTRE {
+                    write!(f, "C")?;
+                } else {
+                    write!(f, "{}", display_number)?;
+                }
+                write!(f, "{}", COLOR_NAMES[color as usize])?;
+                if row_id == 5 {
+                    write!(f, "F")
+                } else {
+                    write!(f, "{}", row_id)
+                }
+            }
+            Action::Place { row_id, col_id } => write!(f, "W{}{}", row_id, col_id),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = ParseActionError;
+
+    fn from_str(notation: &str) -> Result<Action, ParseActionError> {
+        let chars: Vec<char> = notation
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let action = match chars.as_slice() {
+            ['W', row, col] => digit_from_char(*row, 4).and_then(|row_id| {
+                digit_from_char(*col, 4).map(|col_id| Action::Place { row_id, col_id })
+            }),
+            [display, color, dest] => {
+                let display_number = if *display == 'C' {
+                    Some(CENTRE)
+                } else {
+                    digit_from_char(*display, 8)
+                };
+                let row_id = if *dest == 'F' {
+                    Some(5)
+                } else {
+                    digit_from_char(*dest, 4)
+                };
+                match (display_number, color_from_char(*color), row_id) {
+                    (Some(display_number), Some(color), Some(row_id)) => Some(Action::Take {
+                        display_number,
+                        color,
+                        row_id,
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        action.ok_or_else(|| ParseActionError {
+            notation: notation.to_string(),
+        })
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Action, D::Error> {
+        let notation = String::deserialize(deserializer)?;
+        notation.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParsePositionError {
+    message: String,
+}
+
+impl Display for ParsePositionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid position: {}", self.message)
+    }
+}
+
+impl std::error::Error for ParsePositionError {}
+
+impl From<GameError> for ParsePositionError {
+    fn from(err: GameError) -> ParsePositionError {
+        ParsePositionError {
+            message: err.to_string(),
+        }
+    }
+}
+
+fn position_error<T>(message: String) -> Result<T, ParsePositionError> {
+    Err(ParsePositionError { message })
+}
+
+/// Writes the tiles in a display, the centre or a floor, or `-` if there are none.
+fn tiles_to_str(tiles: &[u8]) -> String {
+    let mut output = String::new();
+    for (color, count) in tiles.iter().enumerate() {
+        for _ in 0..*count {
+            output.push(COLOR_NAMES[color]);
+        }
+    }
+    if output.is_empty() {
+        output.push('-');
+    }
+    output
+}
+
+/// Writes the tiles on a floor line in order, or `-` if there are none.
+fn floor_to_str(floor: &[u8]) -> String {
+    if floor.is_empty() {
+        return "-".to_string();
+    }
+    floor
+        .iter()
+        .map(|tile| COLOR_NAMES[*tile as usize])
+        .collect()
+}
+
+fn counts_to_str(counts: &[u8]) -> String {
+    let counts: Vec<String> = counts.iter().map(|count| count.to_string()).collect();
+    counts.join(",")
+}
+
+fn wall_row_to_str(board: &PlayerState, row_id: usize) -> String {
+    let mut output = String::new();
+    let mut num_empty = 0;
+    for col_id in 0..5 {
+        if board.wall_state[row_id][col_id] {
+            if num_empty > 0 {
+                output.push_str(&num_empty.to_string());
+                num_empty = 0;
+            }
+            output.push(COLOR_NAMES[board.wall_colors[row_id][col_id] as usize]);
+        } else {
+            num_empty += 1;
+        }
+    }
+    if num_empty > 0 {
+        output.push_str(&num_empty.to_string());
+    }
+    output
+}
+
+fn board_to_str(board: &PlayerState) -> String {
+    let wall: Vec<String> = (0..5)
+        .map(|row_id| wall_row_to_str(board, row_id))
+        .collect();
+    let rows: Vec<String> = board
+        .rows
+        .iter()
+        .map(|row| {
+            if row.count == 0 {
+                "-".to_string()
+            } else {
+                format!("{}{}", row.count, COLOR_NAMES[row.color as usize])
+            }
+        })
+        .collect();
+    format!(
+        "{}:{}:{}:{}",
+        wall.join(","),
+        rows.join(","),
+        floor_to_str(&board.floor),
+        board.score
+    )
+}
+
+/// Writes the state in position notation.
+pub fn to_position(state: &State) -> String {
+    let boards: Vec<String> = state.board_states.iter().map(board_to_str).collect();
+    let num_factory_displays = state.num_factory_displays() as usize;
+    let displays: Vec<String> = state.central_state.central_state_arr[..num_factory_displays]
+        .iter()
+        .map(|display| tiles_to_str(display))
+        .collect();
+    let to_play = if state.is_finished {
+        format!("{}-", state.player_to_play)
+    } else if state.tiling {
+        format!("{}t", state.player_to_play)
+    } else {
+        state.player_to_play.to_string()
+    };
+    format!(
+        "{} {} {} {} {} {}",
+        boards.join("/"),
+        displays.join(","),
+        tiles_to_str(&state.central_state.central_state_arr[num_factory_displays]),
+        counts_to_str(&state.bag),
+        counts_to_str(&state.lid),
+        to_play
+    )
+}
+
+fn tile_color(c: char, allow_token: bool) -> Result<u8, ParsePositionError> {
+    match COLOR_NAMES.iter().position(|name| *name == c) {
+        Some(5) if !allow_token => position_error("unexpected first player token F".to_string()),
+        Some(color) => Ok(color as u8),
+        None => position_error(format!("{} is not a color", c)),
+    }
+}
+
+fn parse_tiles(field: &str, allow_token: bool) -> Result<[u8; 6], ParsePositionError> {
+    let mut tiles = [0u8; 6];
+    if field != "-" {
+        for c in field.chars() {
+            let count = &mut tiles[tile_color(c, allow_token)? as usize];
+            *count = match count.checked_add(1) {
+                Some(count) => count,
+                None => return position_error(format!("more than 255 {} tiles", c)),
+            };
+        }
+    }
+    Ok(tiles)
+}
+
+fn parse_floor(field: &str) -> Result<Vec<u8>, ParsePositionError> {
+    if field == "-" {
+        return Ok(Vec::new());
+    }
+    field.chars().map(|c| tile_color(c, true)).collect()
+}
+
+fn parse_counts(field: &str) -> Result<[u8; 5], ParsePositionError> {
+    let mut counts = [0; 5];
+    let parts: Vec<&str> = field.split(',').collect();
+    if parts.len() != 5 {
+        return position_error(format!("expected 5 tile counts, got {}", field));
+    }
+    for (count, part) in counts.iter_mut().zip(parts) {
+        *count = match part.parse() {
+            Ok(value) => value,
+            Err(_) => return position_error(format!("{} is not a tile count", part)),
+        };
+    }
+    Ok(counts)
+}
+
+fn parse_wall_row(
+    board: &mut PlayerState,
+    row_id: usize,
+    field: &str,
+) -> Result<(), ParsePositionError> {
+    let mut col_id = 0;
+    for c in field.chars() {
+        if let Some(num_empty) = c.to_digit(10) {
+            col_id += num_empty as usize;
+        } else {
+            if col_id >= 5 {
+                return position_error(format!("wall row {} has more than 5 spaces", field));
+            }
+            board.wall_state[row_id][col_id] = true;
+            board.wall_colors[row_id][col_id] = tile_color(c, false)?;
+            col_id += 1;
+        }
+    }
+    if col_id != 5 {
+        return position_error(format!("wall row {} doesn't have 5 spaces", field));
+    }
+    Ok(())
+}
+
+fn parse_pattern_line(field: &str) -> Result<Row, ParsePositionError> {
+    if field == "-" {
+        return Ok(Row { color: 0, count: 0 });
+    }
+    let mut chars = field.chars();
+    let count = chars.next().and_then(|c| c.to_digit(10));
+    let color = chars.next();
+    match (count, color, chars.next()) {
+        (Some(count), Some(color), None) if count > 0 => Ok(Row {
+            color: tile_color(color, false)?,
+            count: count as u8,
+        }),
+        _ => position_error(format!("{} is not a pattern line", field)),
+    }
+}
+
+fn parse_board(field: &str) -> Result<PlayerState, ParsePositionError> {
+    let parts: Vec<&str> = field.split(':').collect();
+    if parts.len() != 4 {
+        return position_error(format!(
+            "board {} doesn't have a wall, pattern lines, floor and score",
+            field
+        ));
+    }
+    let mut board = PlayerState {
+        wall_state: [[false; 5]; 5],
+        wall_colors: [[0; 5]; 5],
+        rows: [Row { color: 0, count: 0 }; 5],
+        score: 0,
+        floor: parse_floor(parts[2])?,
+    };
+    let wall_rows: Vec<&str> = parts[0].split(',').collect();
+    if wall_rows.len() != 5 {
+        return position_error(format!("wall {} doesn't have 5 rows", parts[0]));
+    }
+    for (row_id, wall_row) in wall_rows.iter().enumerate() {
+        parse_wall_row(&mut board, row_id, wall_row)?;
+    }
+    let rows: Vec<&str> = parts[1].split(',').collect();
+    if rows.len() != 5 {
+        return position_error(format!("{} doesn't have 5 pattern lines", parts[1]));
+    }
+    for (row_id, row) in rows.iter().enumerate() {
+        board.rows[row_id] = parse_pattern_line(row)?;
+    }
+    board.score = match parts[3].parse() {
+        Ok(score) => score,
+        Err(_) => return position_error(format!("{} is not a score", parts[3])),
+    };
+    Ok(board)
+}
+
+/// Reads a state in position notation, checking that it is consistent with the rules.
+pub fn parse_position(position: &str, rules: Arc<RuleSet>) -> Result<State, ParsePositionError> {
+    let fields: Vec<&str> = position.split_whitespace().collect();
+    if fields.len() != 6 {
+        return position_error(format!("expected 6 fields, got {}", fields.len()));
+    }
+    let board_states = fields[0]
+        .split('/')
+        .map(parse_board)
+        .collect::<Result<Vec<PlayerState>, ParsePositionError>>()?;
+    let num_players = board_states.len() as u8;
+    if num_players < MIN_PLAYERS || num_players > MAX_PLAYERS {
+        return position_error(format!("{} players", num_players));
+    }
+    let mut central_state = CentralState::new(num_factory_displays(num_players));
+    let displays: Vec<&str> = fields[1].split(',').collect();
+    if displays.len() != num_factory_displays(num_players) as usize {
+        return position_error(format!(
+            "{} factory displays, but {} players need {}",
+            displays.len(),
+            num_players,
+            num_factory_displays(num_players)
+        ));
+    }
+    for (display_index, display) in displays.iter().enumerate() {
+        central_state.central_state_arr[display_index] = parse_tiles(display, false)?;
+    }
+    central_state.central_state_arr[displays.len()] = parse_tiles(fields[2], true)?;
+
+    let field = fields[5];
+    let (player, suffix) = field.split_at(field.len() - field.ends_with(&['t', '-'][..]) as usize);
+    let player_to_play = match player.parse::<u8>() {
+        Ok(player_to_play) if player_to_play < num_players => player_to_play,
+        _ => return position_error(format!("{} is not a player to play", field)),
+    };
+    let tiling = suffix == "t";
+    let is_finished = suffix == "-";
+    let mut state = State {
+        board_states,
+        central_state,
+        player_to_play,
+        bag: parse_counts(fields[3])?,
+        lid: parse_counts(fields[4])?,
+        is_finished,
+        player_scores: vec![0.; num_players as usize],
+        rules,
+        tiling,
+        hash: 0,
+    };
+    if state.is_finished {
+        set_player_scores(&mut state);
+    }
+    state.hash = compute_hash(&state);
+
+    validate_state(&state)?;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays random games, checking that every action that can be played reads back the same
+    /// from its notation.
+    fn check_action_round_trip(free_wall: bool) {
+        let mut rules = RuleSet::default();
+        rules.free_wall = free_wall;
+        play_random_games(rules, |state, _, _, _| {
+            for action in get_valid_actions(state) {
+                assert_eq!(action.to_string().parse(), Ok(action));
+            }
+        });
+    }
+
+    #[test]
+    fn actions_round_trip() {
+        check_action_round_trip(false);
+    }
+
+    #[test]
+    fn actions_round_trip_free_wall() {
+        check_action_round_trip(true);
+    }
+
+    #[test]
+    fn parse_action() {
+        assert_eq!(
+            "3R2".parse(),
+            Ok(Action::Take {
+                display_number: 3,
+                color: 2,
+                row_id: 2
+            })
+        );
+        assert_eq!(
+            " c b f ".parse(),
+            Ok(Action::Take {
+                display_number: CENTRE,
+                color: 0,
+                row_id: 5
+            })
+        );
+        assert_eq!(
+            "w24".parse(),
+            Ok(Action::Place {
+                row_id: 2,
+                col_id: 4
+            })
+        );
+        // The wrong length, no such display, color, row or wall space, and the first player
+        // token, which can't be taken.
+        let bad_actions = [
+            "", "3R", "3R22", "W5", "9R2", "XR2", "3X2", "3F2", "3R5", "3RX", "CC5", "CFF", "W50",
+            "W05", "WB2",
+        ];
+        for notation in bad_actions.iter() {
+            assert!(notation.parse::<Action>().is_err(), "accepted {}", notation);
+        }
+    }
+
+    /// Plays random games, checking that every state, finished ones included, reads back the same
+    /// from position notation.
+    fn check_position_round_trip(free_wall: bool) {
+        let mut rules = RuleSet::default();
+        rules.free_wall = free_wall;
+        let check = |state: &State| {
+            let position = to_position(state);
+            assert_eq!(
+                parse_position(&position, state.rules.clone()),
+                Ok(state.clone())
+            );
+        };
+        play_random_games(rules, |state, _, next_state, _| {
+            check(state);
+            // Between rounds, before the refill, no move can be played.
+            if next_state.is_finished {
+                check(next_state);
+            }
+        });
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        check_position_round_trip(false);
+    }
+
+    #[test]
+    fn positions_round_trip_free_wall() {
+        check_position_round_trip(true);
+    }
+
+    #[test]
+    fn finished_games_keep_the_player_to_play() {
+        let position =
+            "BYRGC,5,5,5,5:-,-,-,-,-:-:9/5,5,5,5,5:-,-,-,-,-:F:0 -,-,-,-,- - 19,19,19,19,19 0,0,0,0,0 1-";
+        let state = parse_position(position, Arc::new(RuleSet::default())).unwrap();
+        assert!(state.is_finished);
+        assert_eq!(state.player_to_play, 1);
+        assert_eq!(to_position(&state), position);
+    }
+
+    #[test]
+    fn parse_position_rejects_bad_positions() {
+        let rules = Arc::new(RuleSet::default());
+        let start = "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0,0,0,0,0 0";
+        assert!(parse_position(start, rules.clone()).is_ok());
+        let bad_positions = [
+            // A field missing.
+            "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0",
+            // Too many tiles of a color to count.
+            &format!(
+                "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC {} 18,15,16,16,15 0,0,0,0,0 0",
+                "B".repeat(256)
+            ),
+            // The first player token on a factory display.
+            "5,5,5,5,5:-,-,-,-,-:-:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGF,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0,0,0,0,0 0",
+            // The wrong number of factory displays.
+            "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC - 18,15,16,16,15 0,0,0,0,0 0",
+            // A player who isn't playing.
+            "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0,0,0,0,0 2",
+            // A finished game without the player to play.
+            "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0,0,0,0,0 -",
+            // A wall row that's too long.
+            "6,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 18,15,16,16,15 0,0,0,0,0 0",
+            // A pattern line holding more tiles than it has spaces.
+            "5,5,5,5,5:2B,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 16,15,16,16,15 0,0,0,0,0 0",
+            // A tile missing.
+            "5,5,5,5,5:-,-,-,-,-:F:0/5,5,5,5,5:-,-,-,-,-:-:0 YRGC,YYYR,YRCC,BBGC,RGGC - 17,15,16,16,15 0,0,0,0,0 0",
+        ];
+        for position in bad_positions.iter() {
+            assert!(
+                parse_position(position, rules.clone()).is_err(),
+                "accepted {}",
+                position
+            );
+        }
+    }
+}
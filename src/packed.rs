@@ -0,0 +1,694 @@
+//! A compact copy of `State` for fast simulation. The search runs its random playouts on it,
+//! but its tree still holds and steps `State`s, one for each node.
+//!
+//! `PackedState` is `Copy` and doesn't touch the heap. Walls are 25-bit masks with bit
+//! `5 * row + column` for each space, a player's pattern lines and floor line are packed into an
+//! integer each, and each factory display is a `u64` with a byte for the number of tiles of each
+//! color (and the first player token). The rules aren't part of it: `step` takes them.
+//!
+//! `valid_actions`, `fill_valid_actions` and `step` follow `get_valid_actions` and `step` in
+//! `game_state`, giving the actions in the same order, so a playout with the same random numbers
+//! plays the same moves. They don't log scoring events or keep the hash up to date; `to_state`
+//! computes it.
+use std::cmp;
+use std::sync::Arc;
+
+use crate::game_state::*;
+use crate::rules::RuleSet;
+use crate::zobrist::compute_hash;
+
+/// The most tiles a packed floor line holds, at 3 bits each.
+pub const MAX_FLOOR_TILES: usize = 21;
+/// The number of factory displays with the most players, plus the centre.
+const MAX_DISPLAYS: usize = 2 * MAX_PLAYERS as usize + 2;
+
+/// The spaces in row 0 of the wall.
+const ROW_MASK: u32 = 0b11111;
+/// The spaces in column 0 of the wall.
+const COLUMN_MASK: u32 = 0b00001_00001_00001_00001_00001;
+/// The bytes of a display holding the tiles of the five colors, but not the first player token.
+const TILES_MASK: u64 = 0xff_ffff_ffff;
+
+fn wall_bit(row_id: u8, col_id: u8) -> u32 {
+    1 << (5 * row_id + col_id)
+}
+
+/// The mask of the byte holding a color's count on a display.
+fn color_byte(color: u8) -> u64 {
+    0xff << (8 * color)
+}
+
+/// A player's board.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+struct PackedBoard {
+    /// The spaces on the wall with tiles.
+    wall: u32,
+    /// The spaces on the wall with tiles of each color.
+    wall_colors: [u32; 5],
+    /// The pattern lines, 6 bits each starting from row 0: the color in the lower 3 bits and the
+    /// count in the upper 3.
+    rows: u32,
+    /// The tiles on the floor line, 3 bits each in the order they were placed.
+    floor: u64,
+    floor_len: u8,
+    score: i32,
+}
+
+impl PackedBoard {
+    fn from_player_state(board: &PlayerState) -> PackedBoard {
+        let mut packed = PackedBoard {
+            score: board.score,
+            ..Default::default()
+        };
+        for row_id in 0..5 {
+            for col_id in 0..5 {
+                if board.wall_state[row_id as usize][col_id as usize] {
+                    let color = board.wall_colors[row_id as usize][col_id as usize];
+                    packed.wall |= wall_bit(row_id, col_id);
+                    packed.wall_colors[color as usize] |= wall_bit(row_id, col_id);
+                }
+            }
+            packed.set_row(row_id, board.rows[row_id as usize]);
+        }
+        for tile in board.floor.iter() {
+            packed.push_floor(*tile);
+        }
+        packed
+    }
+
+    fn to_player_state(&self) -> PlayerState {
+        let mut board = PlayerState {
+            wall_state: [[false; 5]; 5],
+            wall_colors: [[0; 5]; 5],
+            rows: [Row { color: 0, count: 0 }; 5],
+            score: self.score,
+            floor: (0..self.floor_len)
+                .map(|slot| self.floor_tile(slot))
+                .collect(),
+        };
+        for row_id in 0..5 {
+            for col_id in 0..5 {
+                let bit = wall_bit(row_id, col_id);
+                if self.wall & bit != 0 {
+                    board.wall_state[row_id as usize][col_id as usize] = true;
+                    board.wall_colors[row_id as usize][col_id as usize] = (0..5)
+                        .find(|color| self.wall_colors[*color as usize] & bit != 0)
+                        .unwrap();
+                }
+            }
+            board.rows[row_id as usize] = self.row(row_id);
+        }
+        board
+    }
+
+    fn row(&self, row_id: u8) -> Row {
+        let bits = self.rows >> (6 * row_id);
+        Row {
+            color: (bits & 0b111) as u8,
+            count: ((bits >> 3) & 0b111) as u8,
+        }
+    }
+
+    fn set_row(&mut self, row_id: u8, row: Row) {
+        let shift = 6 * row_id;
+        self.rows &= !(0b111111 << shift);
+        self.rows |= (row.color as u32 | (row.count as u32) << 3) << shift;
+    }
+
+    fn row_has_color(&self, row_id: u8, color: u8) -> bool {
+        self.wall_colors[color as usize] & (ROW_MASK << (5 * row_id)) != 0
+    }
+
+    /// The columns where a tile of the given color may be placed in the given row of a free
+    /// wall, as a mask with bit `column`.
+    fn free_wall_columns(&self, row_id: u8, color: u8) -> u32 {
+        let empty = !(self.wall >> (5 * row_id)) & ROW_MASK;
+        let with_color = self.wall_colors[color as usize];
+        let columns_with_color =
+            (with_color | with_color >> 5 | with_color >> 10 | with_color >> 15 | with_color >> 20)
+                & ROW_MASK;
+        empty & !columns_with_color
+    }
+
+    fn floor_tile(&self, slot: u8) -> u8 {
+        ((self.floor >> (3 * slot)) & 0b111) as u8
+    }
+
+    fn push_floor(&mut self, tile: u8) {
+        self.floor |= (tile as u64) << (3 * self.floor_len);
+        self.floor_len += 1;
+    }
+
+    fn pop_floor(&mut self) -> Option<u8> {
+        if self.floor_len == 0 {
+            return None;
+        }
+        self.floor_len -= 1;
+        let tile = self.floor_tile(self.floor_len);
+        self.floor &= !(0b111 << (3 * self.floor_len));
+        Some(tile)
+    }
+
+    fn has_token(&self) -> bool {
+        (0..self.floor_len).any(|slot| self.floor_tile(slot) == FIRST_PLAYER_TOKEN)
+    }
+
+    /// Puts tiles on the floor line. The ones that don't fit go to the lid.
+    fn put_on_floor(&mut self, lid: &mut [u8; 5], color: u8, count: u8, capacity: usize) {
+        let num_fitting = cmp::min(
+            count as usize,
+            capacity.saturating_sub(self.floor_len as usize),
+        );
+        for _ in 0..num_fitting {
+            self.push_floor(color);
+        }
+        lid[color as usize] += count - num_fitting as u8;
+    }
+
+    /// Puts the first player token on the floor line. If the line is full, the last tile on it
+    /// goes to the lid to make room.
+    fn put_token_on_floor(&mut self, lid: &mut [u8; 5], capacity: usize) {
+        if self.floor_len as usize >= capacity {
+            if let Some(tile) = self.pop_floor() {
+                lid[tile as usize] += 1;
+            }
+        }
+        self.push_floor(FIRST_PLAYER_TOKEN);
+    }
+
+    fn score_and_move_floor_tiles(&mut self, lid: &mut [u8; 5], floor_penalties: &[i32]) {
+        let mut penalty = 0;
+        for slot in 0..self.floor_len {
+            penalty += floor_penalties.get(slot as usize).cloned().unwrap_or(0);
+            let tile = self.floor_tile(slot);
+            if tile != FIRST_PLAYER_TOKEN {
+                lid[tile as usize] += 1;
+            }
+        }
+        let has_token = self.has_token();
+        self.floor = 0;
+        self.floor_len = 0;
+        if has_token {
+            // The first player token stays with its holder.
+            self.push_floor(FIRST_PLAYER_TOKEN);
+        }
+        self.score = cmp::max(self.score - penalty, 0);
+    }
+
+    /// The lengths of the horizontal and vertical lines of adjacent tiles on the wall through a
+    /// tile.
+    fn tile_chains(&self, row_id: u8, col_id: u8) -> (u8, u8) {
+        let has_tile = |row_id: u8, col_id: u8| self.wall & wall_bit(row_id, col_id) != 0;
+        let mut horizontal = 1;
+        let mut pos = col_id;
+        while pos > 0 && has_tile(row_id, pos - 1) {
+            pos -= 1;
+            horizontal += 1;
+        }
+        pos = col_id;
+        while pos < 4 && has_tile(row_id, pos + 1) {
+            pos += 1;
+            horizontal += 1;
+        }
+        let mut vertical = 1;
+        pos = row_id;
+        while pos > 0 && has_tile(pos - 1, col_id) {
+            pos -= 1;
+            vertical += 1;
+        }
+        pos = row_id;
+        while pos < 4 && has_tile(pos + 1, col_id) {
+            pos += 1;
+            vertical += 1;
+        }
+        (horizontal, vertical)
+    }
+
+    /// Moves all but one tile from a complete row to the lid and the other tile to the wall.
+    fn move_row_to_wall(&mut self, lid: &mut [u8; 5], row_id: u8, col_id: u8) {
+        let row = self.row(row_id);
+        lid[row.color as usize] += row.count - 1;
+        self.wall |= wall_bit(row_id, col_id);
+        self.wall_colors[row.color as usize] |= wall_bit(row_id, col_id);
+        self.set_row(row_id, Row { color: 0, count: 0 });
+        let (horizontal, vertical) = self.tile_chains(row_id, col_id);
+        self.score += if cmp::min(horizontal, vertical) == 1 {
+            cmp::max(horizontal, vertical) as i32
+        } else {
+            (horizontal + vertical) as i32
+        };
+    }
+
+    fn has_complete_row(&self) -> bool {
+        (0..5).any(|row_id| (self.wall >> (5 * row_id)) & ROW_MASK == ROW_MASK)
+    }
+
+    fn score_bonuses(&mut self, rules: &RuleSet) {
+        for row_id in 0..5 {
+            if (self.wall >> (5 * row_id)) & ROW_MASK == ROW_MASK {
+                self.score += rules.row_bonus;
+            }
+        }
+        for col_id in 0..5 {
+            if (self.wall >> col_id) & COLUMN_MASK == COLUMN_MASK {
+                self.score += rules.column_bonus;
+            }
+        }
+        for color in 0..5 {
+            if (0..5).all(|row_id| self.row_has_color(row_id, color)) {
+                self.score += rules.color_bonus;
+            }
+        }
+    }
+}
+
+/// A state packed for fast simulation. See the module documentation.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PackedState {
+    boards: [PackedBoard; MAX_PLAYERS as usize],
+    /// The tiles on each factory display and then the centre.
+    displays: [u64; MAX_DISPLAYS],
+    num_players: u8,
+    num_factory_displays: u8,
+    player_to_play: u8,
+    bag: [u8; 5],
+    lid: [u8; 5],
+    is_finished: bool,
+    tiling: bool,
+}
+
+impl PackedState {
+    /// Packs a state, or returns None if its floor lines can hold more than `MAX_FLOOR_TILES`
+    /// tiles.
+    pub fn from_state(state: &State) -> Option<PackedState> {
+        let max_floor_len = cmp::max(state.rules.floor_capacity(), 1);
+        if max_floor_len > MAX_FLOOR_TILES
+            || state.num_players() > MAX_PLAYERS
+            || state.central_state.central_state_arr.len() > MAX_DISPLAYS
+        {
+            return None;
+        }
+        let mut packed = PackedState {
+            boards: [Default::default(); MAX_PLAYERS as usize],
+            displays: [0; MAX_DISPLAYS],
+            num_players: state.num_players(),
+            num_factory_displays: state.num_factory_displays(),
+            player_to_play: state.player_to_play,
+            bag: state.bag,
+            lid: state.lid,
+            is_finished: state.is_finished,
+            tiling: state.tiling,
+        };
+        for (player_id, board) in state.board_states.iter().enumerate() {
+            packed.boards[player_id] = PackedBoard::from_player_state(board);
+        }
+        for (display_index, display) in state.central_state.central_state_arr.iter().enumerate() {
+            for (color, count) in display.iter().enumerate() {
+                packed.displays[display_index] |= (*count as u64) << (8 * color);
+            }
+        }
+        Some(packed)
+    }
+
+    /// Unpacks the state, for a game with the given rules.
+    pub fn to_state(&self, rules: Arc<RuleSet>) -> State {
+        let mut central_state = CentralState::new(self.num_factory_displays);
+        for (display_index, display) in central_state.central_state_arr.iter_mut().enumerate() {
+            for (color, count) in display.iter_mut().enumerate() {
+                *count = (self.displays[display_index] >> (8 * color)) as u8;
+            }
+        }
+        let mut state = State {
+            board_states: self.boards[..self.num_players as usize]
+                .iter()
+                .map(|board| board.to_player_state())
+                .collect(),
+            central_state,
+            player_to_play: self.player_to_play,
+            bag: self.bag,
+            lid: self.lid,
+            is_finished: self.is_finished,
+            player_scores: vec![0.; self.num_players as usize],
+            rules,
+            tiling: self.tiling,
+            hash: 0,
+        };
+        if state.is_finished {
+            set_player_scores(&mut state);
+        }
+        state.hash = compute_hash(&state);
+        state
+    }
+
+    pub fn num_players(&self) -> u8 {
+        self.num_players
+    }
+
+    pub fn player_to_play(&self) -> u8 {
+        self.player_to_play
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.is_finished
+    }
+
+    fn centre(&self) -> usize {
+        self.num_factory_displays as usize
+    }
+
+    /// Whether no tiles have been taken yet this round, as `is_first_move` in `game_state` checks.
+    fn is_first_move(&self) -> bool {
+        self.boards[..self.num_players as usize]
+            .iter()
+            .all(|board| {
+                (0..5).all(|row_id| board.row(row_id).count == 0)
+                    && (0..board.floor_len).all(|slot| board.floor_tile(slot) == FIRST_PLAYER_TOKEN)
+            })
+    }
+
+    /// Whether there are tiles on the factory displays or in the centre. The first player token
+    /// doesn't count.
+    fn has_tiles_left(&self) -> bool {
+        self.displays[..=self.centre()]
+            .iter()
+            .any(|display| display & TILES_MASK != 0)
+    }
+
+    /// The row being moved to the wall by the player to play while tiling, with the columns it
+    /// may go in as a mask with bit `column`.
+    fn tiling_columns(&self) -> Option<(u8, u32)> {
+        let board = &self.boards[self.player_to_play as usize];
+        (0..5)
+            .find(|row_id| board.row(*row_id).count == row_id + 1)
+            .map(|row_id| {
+                let columns = board.free_wall_columns(row_id, board.row(row_id).color);
+                (row_id, columns)
+            })
+    }
+
+    /// The rows (bit `row_id`, with the floor as row 5) where the player to play may put each
+    /// color.
+    fn take_destinations(&self) -> [u8; 5] {
+        let board = &self.boards[self.player_to_play as usize];
+        let mut dests = [1 << 5; 5];
+        for row_id in 0..5 {
+            let row = board.row(row_id);
+            if row.count > 0 {
+                if row.count < row_id + 1 {
+                    dests[row.color as usize] |= 1 << row_id;
+                }
+            } else {
+                for (color, dest) in dests.iter_mut().enumerate() {
+                    if !board.row_has_color(row_id, color as u8) {
+                        *dest |= 1 << row_id;
+                    }
+                }
+            }
+        }
+        dests
+    }
+
+    /// Calls `f` with each action that can be played, in the same order as `get_valid_actions`.
+    fn for_each_valid_action<F: FnMut(Action)>(&self, mut f: F) {
+        if self.tiling {
+            if let Some((row_id, columns)) = self.tiling_columns() {
+                for col_id in 0..5 {
+                    if columns & (1 << col_id) != 0 {
+                        f(Action::Place { row_id, col_id });
+                    }
+                }
+            }
+            return;
+        }
+
+        let dests = self.take_destinations();
+        for display_index in 0..=self.centre() {
+            let display_number = if display_index == self.centre() {
+                CENTRE
+            } else {
+                display_index as u8
+            };
+            let display = self.displays[display_index];
+            for (color, dest) in dests.iter().enumerate() {
+                if display & color_byte(color as u8) == 0 {
+                    continue;
+                }
+                for row_id in 0..6 {
+                    if dest & (1 << row_id) != 0 {
+                        f(Action::Take {
+                            display_number,
+                            color: color as u8,
+                            row_id,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gets the actions that can be played, in the same order as `get_valid_actions`.
+    pub fn valid_actions(&self) -> Vec<Action> {
+        let mut valid_actions = Vec::with_capacity(self.count_legal_actions());
+        self.for_each_valid_action(|action| valid_actions.push(action));
+        valid_actions
+    }
+
+    /// Puts the actions that can be played in `buffer`, replacing its contents, without
+    /// allocating.
+    pub fn fill_valid_actions(&self, buffer: &mut ActionBuffer) {
+        buffer.clear();
+        self.for_each_valid_action(|action| buffer.push(action));
+    }
+
+    /// The number of actions that can be played, without listing them.
+    pub fn count_legal_actions(&self) -> usize {
+        if self.tiling {
+            return self
+                .tiling_columns()
+                .map_or(0, |(_, columns)| columns.count_ones() as usize);
+        }
+        let dests = self.take_destinations();
+        self.displays[..=self.centre()]
+            .iter()
+            .map(|display| {
+                (0..5)
+                    .filter(|color| display & color_byte(*color) != 0)
+                    .map(|color| dests[color as usize].count_ones() as usize)
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Whether any action can be played.
+    pub fn has_legal_action(&self) -> bool {
+        if self.tiling {
+            self.count_legal_actions() > 0
+        } else {
+            // Tiles can always go on the floor.
+            self.has_tiles_left()
+        }
+    }
+
+    /// Plays an action, which must be legal. Doesn't refill the factory displays.
+    ///
+    /// Returns whether the round is over, as `step` does.
+    pub fn step(&mut self, action: Action, rules: &RuleSet) -> bool {
+        debug_assert!({
+            let mut is_valid = false;
+            self.for_each_valid_action(|valid_action| is_valid |= valid_action == action);
+            is_valid
+        });
+        let round_over = match action {
+            Action::Take {
+                display_number,
+                color,
+                row_id,
+            } => {
+                self.take_tiles(display_number, color, row_id, rules.floor_capacity());
+                if self.has_tiles_left() {
+                    false
+                } else if rules.free_wall {
+                    self.tiling = true;
+                    self.advance_tiling(rules)
+                } else {
+                    self.score_and_reset(rules);
+                    true
+                }
+            }
+            Action::Place { row_id, col_id } => {
+                self.boards[self.player_to_play as usize].move_row_to_wall(
+                    &mut self.lid,
+                    row_id,
+                    col_id,
+                );
+                self.advance_tiling(rules)
+            }
+        };
+        if round_over {
+            self.is_finished = self.is_game_over();
+            if self.is_finished {
+                for board in self.boards[..self.num_players as usize].iter_mut() {
+                    board.score_bonuses(rules);
+                }
+            }
+        }
+        round_over
+    }
+
+    /// Takes tiles from a display and puts them on a row or the floor.
+    fn take_tiles(&mut self, display_number: u8, color: u8, row_id: u8, capacity: usize) {
+        let centre = self.centre();
+        let display_index = if display_number == CENTRE {
+            centre
+        } else {
+            display_number as usize
+        };
+        let is_first_move = self.is_first_move();
+        let board = &mut self.boards[self.player_to_play as usize];
+        let token = color_byte(FIRST_PLAYER_TOKEN);
+        if is_first_move && board.has_token() {
+            // Move the first player token to the centre.
+            board.floor = 0;
+            board.floor_len = 0;
+            self.displays[centre] |= 1 << (8 * FIRST_PLAYER_TOKEN);
+        }
+        let display = self.displays[display_index];
+        let num_tiles = ((display & color_byte(color)) >> (8 * color)) as u8;
+        if display_index == centre {
+            if display & token != 0 {
+                // If the first player token is in the centre, give it to the first player.
+                board.put_token_on_floor(&mut self.lid, capacity);
+            }
+            self.displays[centre] &= !(color_byte(color) | token);
+        } else {
+            // Move the other tiles from the factory display to the centre. No count can
+            // overflow into the next byte, as there are at most 255 tiles of a color.
+            self.displays[centre] += display & !color_byte(color);
+            self.displays[display_index] = 0;
+        }
+
+        // Give the player the requested tiles. The ones that don't fit on the row go on the floor.
+        let num_on_floor = if row_id < 5 {
+            let mut row = board.row(row_id);
+            let num_on_row = cmp::min(num_tiles, row_id + 1 - row.count);
+            row.count += num_on_row;
+            row.color = color;
+            board.set_row(row_id, row);
+            num_tiles - num_on_row
+        } else {
+            num_tiles
+        };
+        board.put_on_floor(&mut self.lid, color, num_on_floor, capacity);
+
+        self.player_to_play = (self.player_to_play + 1) % self.num_players;
+    }
+
+    /// Scores the round and resets the boards, as `score_and_reset` does.
+    fn score_and_reset(&mut self, rules: &RuleSet) {
+        for player_id in 0..self.num_players {
+            if self.boards[player_id as usize].has_token() {
+                self.player_to_play = player_id;
+            }
+        }
+        for board in self.boards[..self.num_players as usize].iter_mut() {
+            for row_id in 0..5 {
+                let row = board.row(row_id);
+                if row.count < row_id + 1 {
+                    continue;
+                }
+                debug_assert!(!rules.free_wall);
+                board.move_row_to_wall(&mut self.lid, row_id, fixed_wall_column(row_id, row.color));
+            }
+            board.score_and_move_floor_tiles(&mut self.lid, &rules.floor_penalties);
+        }
+    }
+
+    /// Finds the next complete row to be moved to a free wall, as `advance_tiling` does.
+    ///
+    /// Returns whether tiling is over, in which case the round has been scored and reset.
+    fn advance_tiling(&mut self, rules: &RuleSet) -> bool {
+        for player_id in 0..self.num_players {
+            let board = &mut self.boards[player_id as usize];
+            for row_id in 0..5 {
+                let row = board.row(row_id);
+                if row.count < row_id + 1 {
+                    continue;
+                }
+                if board.free_wall_columns(row_id, row.color) != 0 {
+                    self.player_to_play = player_id;
+                    return false;
+                }
+                board.put_on_floor(&mut self.lid, row.color, row.count, rules.floor_capacity());
+                board.set_row(row_id, Row { color: 0, count: 0 });
+            }
+        }
+        self.tiling = false;
+        self.score_and_reset(rules);
+        true
+    }
+
+    /// Whether the game is over, at the end of a round: a player has a complete horizontal row,
+    /// or there are no tiles left to refill with.
+    fn is_game_over(&self) -> bool {
+        debug_assert!(!self.has_tiles_left());
+        self.boards[..self.num_players as usize]
+            .iter()
+            .any(|board| board.has_complete_row())
+            || self
+                .bag
+                .iter()
+                .chain(self.lid.iter())
+                .all(|count| *count == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays random games on a `State` and a `PackedState` side by side, checking that they give
+    /// the same actions and states.
+    fn check_random_games(rules: RuleSet) {
+        // A packed copy of the state at the start of each round, stepped along with it.
+        let mut packed: Option<PackedState> = None;
+        let mut buffer = ActionBuffer::new();
+        play_random_games(rules, |state, action, next_state, round_over| {
+            let packed_state =
+                packed.get_or_insert_with(|| PackedState::from_state(state).unwrap());
+            let valid_actions = get_valid_actions(state);
+            packed_state.fill_valid_actions(&mut buffer);
+            assert_eq!(&buffer[..], &valid_actions[..]);
+            assert_eq!(packed_state.count_legal_actions(), valid_actions.len());
+            assert_eq!(packed_state.step(action, &state.rules), round_over);
+            assert_eq!(
+                packed_state.to_state(state.rules.clone()),
+                *next_state,
+                "after {}",
+                action
+            );
+            assert_eq!(packed_state.is_finished(), next_state.is_finished);
+            if round_over {
+                packed = None;
+            }
+        });
+    }
+
+    #[test]
+    fn playouts_match_state() {
+        check_random_games(RuleSet::default());
+    }
+
+    #[test]
+    fn playouts_match_state_free_wall() {
+        let mut rules = RuleSet::default();
+        rules.free_wall = true;
+        check_random_games(rules);
+    }
+
+    #[test]
+    fn playouts_match_state_short_floor() {
+        let mut rules = RuleSet::default();
+        rules.floor_penalties = vec![2, 3];
+        check_random_games(rules);
+    }
+}
@@ -0,0 +1,272 @@
+//! Game records, from which games can be resumed and replayed.
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+use crate::game_state::*;
+use crate::scoring::*;
+use crate::zobrist::compute_hash;
+
+/// The tiles put on the factory displays at the start of a round, and the bag and lid left
+/// afterwards.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Refill {
+    pub displays: Vec<[u8; 6]>,
+    pub bag: [u8; 5],
+    pub lid: [u8; 5],
+}
+
+impl Refill {
+    /// Records the refill that has just been made in the given state.
+    pub fn from_state(state: &State) -> Refill {
+        let num_factory_displays = state.num_factory_displays() as usize;
+        Refill {
+            displays: state.central_state.central_state_arr[..num_factory_displays].to_vec(),
+            bag: state.bag,
+            lid: state.lid,
+        }
+    }
+
+    /// Makes the same refill in a state whose round has just ended.
+    pub fn apply(&self, state: &mut State) {
+        let num_factory_displays = state.num_factory_displays() as usize;
+        assert!(
+            self.displays.len() == num_factory_displays,
+            "Refill is for {} factory displays, but the game has {}.",
+            self.displays.len(),
+            num_factory_displays
+        );
+        state.central_state.central_state_arr[..num_factory_displays]
+            .copy_from_slice(&self.displays);
+        state.bag = self.bag;
+        state.lid = self.lid;
+        state.hash = compute_hash(state);
+        #[cfg(debug_assertions)]
+        check_counts(&state);
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum RecordEntry {
+    Action(Action),
+    Refill(Refill),
+}
+
+/// Everything that happened in a game, from which it can be replayed exactly.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct GameRecord {
+    /// The state at the start of the game, with the factory displays filled.
+    pub initial_state: State,
+    /// The actions played and the refills between rounds, in order.
+    pub entries: Vec<RecordEntry>,
+    /// Each player's score, once the game is finished.
+    pub final_scores: Option<Vec<i32>>,
+    /// The seed of the random number generator the game was played with, if known.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+impl GameRecord {
+    pub fn new(initial_state: &State, seed: Option<u64>) -> GameRecord {
+        GameRecord {
+            initial_state: initial_state.clone(),
+            entries: Vec::new(),
+            final_scores: None,
+            seed,
+        }
+    }
+
+    pub fn record_action(&mut self, action: Action) {
+        self.entries.push(RecordEntry::Action(action));
+    }
+
+    /// Records the final scores if the game is finished.
+    pub fn record_result(&mut self, state: &State) {
+        if state.is_finished {
+            self.final_scores = Some(state.board_states.iter().map(|b| b.score).collect());
+        }
+    }
+
+    /// Steps through the game, giving the state after each entry.
+    pub fn replay(&self) -> Replay {
+        Replay {
+            entries: self.entries.iter(),
+            state: self.initial_state.clone(),
+        }
+    }
+
+    /// The scoring events of the game, each with the index of the entry that caused it.
+    pub fn score_events(&self) -> Vec<(usize, ScoreEvent)> {
+        let mut events = Vec::new();
+        let mut state = self.initial_state.clone();
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry {
+                RecordEntry::Action(action) => {
+                    let mut entry_events = Vec::new();
+                    state = step_with_log(state, *action, true, &mut entry_events).0;
+                    events.extend(entry_events.into_iter().map(|event| (index, event)));
+                }
+                RecordEntry::Refill(refill) => refill.apply(&mut state),
+            }
+        }
+        events
+    }
+
+    /// The state after the last entry, from which the game can be resumed.
+    pub fn final_state(&self) -> State {
+        let mut replay = self.replay();
+        while replay.next().is_some() {}
+        replay.state
+    }
+
+    /// Writes the record to a JSON file.
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        let contents = serde_json::to_string_pretty(&self)?;
+        std::fs::File::create(filename)?.write_all(contents.as_bytes())
+    }
+
+    /// Checks that the initial state is valid and that every action in the record is legal and
+    /// every refill can be made, so that the game can be replayed.
+    pub fn check(&self) -> Result<(), GameError> {
+        validate_state(&self.initial_state)?;
+        let mut state = self.initial_state.clone();
+        for entry in self.entries.iter() {
+            match entry {
+                RecordEntry::Action(action) => state = try_step(&state, *action)?.0,
+                RecordEntry::Refill(refill) => {
+                    if refill.displays.len() != state.num_factory_displays() as usize {
+                        return Err(GameError::InvalidState(format!(
+                            "a refill is for {} factory displays, but the game has {}",
+                            refill.displays.len(),
+                            state.num_factory_displays()
+                        )));
+                    }
+                    let mut refilled = state.clone();
+                    refilled.central_state.central_state_arr[..refill.displays.len()]
+                        .copy_from_slice(&refill.displays);
+                    refilled.bag = refill.bag;
+                    refilled.lid = refill.lid;
+                    refilled.hash = compute_hash(&refilled);
+                    check_tile_counts(&refilled)?;
+                    state = refilled;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a record from a JSON file, checking that it can be replayed.
+    pub fn load(filename: &str) -> std::io::Result<GameRecord> {
+        let mut contents = String::new();
+        std::fs::File::open(filename)?.read_to_string(&mut contents)?;
+        let mut record: GameRecord = serde_json::from_str(&contents)?;
+        record.initial_state.hash = compute_hash(&record.initial_state);
+        record
+            .check()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        Ok(record)
+    }
+}
+
+/// Iterator over the states of a recorded game.
+pub struct Replay<'a> {
+    entries: std::slice::Iter<'a, RecordEntry>,
+    state: State,
+}
+
+impl<'a> Iterator for Replay<'a> {
+    type Item = (&'a RecordEntry, State);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next()?;
+        match entry {
+            RecordEntry::Action(action) => {
+                let (new_state, _round_over) = step(self.state.clone(), *action, true);
+                self.state = new_state;
+            }
+            RecordEntry::Refill(refill) => refill.apply(&mut self.state),
+        }
+        Some((entry, self.state.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Game;
+    use crate::rules::RuleSet;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::Arc;
+
+    /// Plays a seeded game with random moves, giving the game and the state after each record
+    /// entry.
+    fn play_game(seed: u64) -> (Game, Vec<State>) {
+        let mut game = Game::new(3, Arc::new(RuleSet::default()), seed);
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut states = Vec::new();
+        while !game.is_finished() {
+            let legal_actions = game.legal_actions();
+            let action = legal_actions[rng.gen_range(0, legal_actions.len())];
+            let round = game.round();
+            let mut state = game.state().clone();
+            game.play(action).unwrap();
+            if game.round() != round {
+                // The state at the end of the round, before the refill.
+                state = step(state, action, true).0;
+                states.push(state);
+            }
+            states.push(game.state().clone());
+        }
+        (game, states)
+    }
+
+    /// A file name for a test to save a record to.
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("amarillo-{}-{}.json", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn saved_games_load_and_replay() {
+        let (game, states) = play_game(1);
+        let filename = temp_file("replay");
+        game.record().save(&filename).unwrap();
+        let record = GameRecord::load(&filename);
+        std::fs::remove_file(&filename).unwrap();
+        let record = record.unwrap();
+        assert_eq!(&record, game.record());
+        assert_eq!(record.final_scores, Some(game.scores()));
+        let replayed: Vec<State> = record.replay().map(|(_, state)| state).collect();
+        assert_eq!(replayed, states);
+        assert_eq!(&record.final_state(), game.state());
+        let resumed = Game::from_record(record, 0).unwrap();
+        assert_eq!(resumed.state(), game.state());
+        assert_eq!(resumed.moves(), game.moves());
+    }
+
+    #[test]
+    fn load_rejects_bad_records() {
+        let (game, _) = play_game(2);
+        let filename = temp_file("bad");
+
+        // An action that can't be played: taking the same tiles twice.
+        let mut record = game.record().clone();
+        record.entries[1] = record.entries[0].clone();
+        record.save(&filename).unwrap();
+        let err = GameRecord::load(&filename).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Rules that can't be played with.
+        let mut record = game.record().clone();
+        let mut rules = RuleSet::default();
+        rules.floor_penalties.clear();
+        record.initial_state.rules = Arc::new(rules);
+        record.save(&filename).unwrap();
+        let err = GameRecord::load(&filename).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
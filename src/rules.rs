@@ -0,0 +1,90 @@
+//! Configurable rules.
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// The rules of a game: the numbers of tiles and the scoring.
+///
+/// The default is the official rule set. Fields missing from a rule set file take their
+/// official values.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RuleSet {
+    /// The number of tiles of each color in the game.
+    pub tiles_per_color: u8,
+    /// The number of tiles put on each factory display at the start of a round.
+    pub tiles_per_display: u8,
+    /// Bonus for each complete horizontal row on the wall at the end of the game.
+    pub row_bonus: i32,
+    /// Bonus for each complete vertical column on the wall at the end of the game.
+    pub column_bonus: i32,
+    /// Bonus for each color with all five tiles on the wall at the end of the game.
+    pub color_bonus: i32,
+    /// The points lost for each space on the floor line, from the left. The floor line has one
+    /// space for each penalty, and the tiles that don't fit go to the lid.
+    pub floor_penalties: Vec<i32>,
+    /// Play the free placement ("grey wall") variant, in which the wall has no color pattern.
+    /// At the end of the round, the player chooses where the tile from each complete row goes: any
+    /// empty space in that row whose column doesn't already contain a tile of the same color.
+    pub free_wall: bool,
+}
+
+impl Default for RuleSet {
+    fn default() -> RuleSet {
+        RuleSet {
+            tiles_per_color: 20,
+            tiles_per_display: 4,
+            row_bonus: 2,
+            column_bonus: 7,
+            color_bonus: 10,
+            floor_penalties: vec![1, 1, 2, 2, 2, 3, 3],
+            free_wall: false,
+        }
+    }
+}
+
+impl RuleSet {
+    /// The number of tiles the floor line holds.
+    pub fn floor_capacity(&self) -> usize {
+        self.floor_penalties.len()
+    }
+
+    /// Loads a rule set from a JSON file.
+    pub fn from_file(filename: &str) -> std::io::Result<RuleSet> {
+        let mut contents = String::new();
+        std::fs::File::open(filename)?.read_to_string(&mut contents)?;
+        let rules: RuleSet = serde_json::from_str(&contents)?;
+        rules
+            .validate()
+            .map_err(|message| std::io::Error::new(std::io::ErrorKind::InvalidData, message))?;
+        Ok(rules)
+    }
+
+    /// The rule set for the command line options of the binaries: the rules in `filename`, or
+    /// the official rules if there's none, switched to the free placement variant if `free_wall`.
+    pub fn from_options(filename: Option<&str>, free_wall: bool) -> Result<RuleSet, String> {
+        let mut rules = match filename {
+            Some(filename) => RuleSet::from_file(filename)
+                .map_err(|err| format!("Could not load the rule set from {}: {}", filename, err))?,
+            None => RuleSet::default(),
+        };
+        if free_wall {
+            rules.free_wall = true;
+        }
+        Ok(rules)
+    }
+
+    /// Checks that a game can be played with the rules, returning what's wrong with them if not.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tiles_per_color == 0 || self.tiles_per_color > 50 {
+            return Err("tiles_per_color must be between 1 and 50".to_string());
+        }
+        if self.tiles_per_display == 0 {
+            return Err("tiles_per_display must be at least 1".to_string());
+        }
+        // The first player token always goes on the floor line, so it needs a space.
+        if self.floor_penalties.is_empty() {
+            return Err("floor_penalties must have at least one space".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Scoring events, which say where each player's points came from.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Display;
+
+use crate::game_state::COLOR_NAMES;
+
+/// A change to a player's score.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ScoreEvent {
+    /// A tile was moved from a complete row to the wall. `horizontal` and `vertical` are the
+    /// lengths of the lines of adjacent tiles it is part of, counting itself.
+    TilePlaced {
+        player: u8,
+        row_id: u8,
+        col_id: u8,
+        color: u8,
+        horizontal: u8,
+        vertical: u8,
+        points: i32,
+    },
+    /// Points were lost for the tiles on the floor (5 for the first player token), in order.
+    /// `points` is the change to the score, which is less than the penalty if the score would
+    /// have gone below zero.
+    FloorPenalty {
+        player: u8,
+        tiles: Vec<u8>,
+        penalty: i32,
+        points: i32,
+    },
+    /// End of game bonus for a complete horizontal row.
+    RowBonus { player: u8, row_id: u8, points: i32 },
+    /// End of game bonus for a complete vertical column.
+    ColumnBonus { player: u8, col_id: u8, points: i32 },
+    /// End of game bonus for having all five tiles of a color on the wall.
+    ColorBonus { player: u8, color: u8, points: i32 },
+}
+
+impl ScoreEvent {
+    /// The player whose score changed.
+    pub fn player(&self) -> u8 {
+        match self {
+            ScoreEvent::TilePlaced { player, .. }
+            | ScoreEvent::FloorPenalty { player, .. }
+            | ScoreEvent::RowBonus { player, .. }
+            | ScoreEvent::ColumnBonus { player, .. }
+            | ScoreEvent::ColorBonus { player, .. } => *player,
+        }
+    }
+
+    /// The change to the player's score.
+    pub fn points(&self) -> i32 {
+        match self {
+            ScoreEvent::TilePlaced { points, .. }
+            | ScoreEvent::FloorPenalty { points, .. }
+            | ScoreEvent::RowBonus { points, .. }
+            | ScoreEvent::ColumnBonus { points, .. }
+            | ScoreEvent::ColorBonus { points, .. } => *points,
+        }
+    }
+}
+
+impl Display for ScoreEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScoreEvent::TilePlaced {
+                player,
+                row_id,
+                col_id,
+                color,
+                horizontal,
+                vertical,
+                points,
+            } => write!(
+                f,
+                "Player {} places {} in row {}, column {} ({} across, {} down): {:+}",
+                player, COLOR_NAMES[*color as usize], row_id, col_id, horizontal, vertical, points
+            ),
+            ScoreEvent::FloorPenalty {
+                player,
+                tiles,
+                penalty,
+                points,
+            } => {
+                let tiles: String = tiles.iter().map(|t| COLOR_NAMES[*t as usize]).collect();
+                write!(
+                    f,
+                    "Player {} has {} on the floor: {:+}",
+                    player, tiles, points
+                )?;
+                if *points != -*penalty {
+                    write!(f, " (of {})", -penalty)?;
+                }
+                Ok(())
+            }
+            ScoreEvent::RowBonus {
+                player,
+                row_id,
+                points,
+            } => write!(
+                f,
+                "Player {} completed row {}: {:+}",
+                player, row_id, points
+            ),
+            ScoreEvent::ColumnBonus {
+                player,
+                col_id,
+                points,
+            } => write!(
+                f,
+                "Player {} completed column {}: {:+}",
+                player, col_id, points
+            ),
+            ScoreEvent::ColorBonus {
+                player,
+                color,
+                points,
+            } => write!(
+                f,
+                "Player {} has all five {} tiles: {:+}",
+                player, COLOR_NAMES[*color as usize], points
+            ),
+        }
+    }
+}
+
+/// Receives scoring events as they happen.
+///
+/// `()` ignores them, which is what the search uses, and a `Vec<ScoreEvent>` collects them.
+pub trait ScoreLog {
+    fn log(&mut self, event: ScoreEvent);
+
+    /// Whether the events are kept, so that they're worth building.
+    fn wants_events(&self) -> bool {
+        true
+    }
+}
+
+impl ScoreLog for () {
+    fn log(&mut self, _event: ScoreEvent) {}
+
+    fn wants_events(&self) -> bool {
+        false
+    }
+}
+
+impl ScoreLog for Vec<ScoreEvent> {
+    fn log(&mut self, event: ScoreEvent) {
+        self.push(event);
+    }
+}
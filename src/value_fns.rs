@@ -1,43 +1,53 @@
+//! Value functions, which estimate each player's chance of winning from a state.
 use std::io::Read;
 
 use crate::game_state::*;
 pub trait ValueFunction {
-    fn get_value(&mut self, state: &State) -> [f32; 3] {
+    /// Gives each player's probability of winning from the given state.
+    fn get_value(&mut self, state: &State) -> Vec<f32> {
         // Use the final score if the game is finished.
         if state.is_finished {
-            return state.player_scores;
+            return state.player_scores.clone();
         }
         self.get_in_progress_value(&state)
     }
-    fn get_in_progress_value(&mut self, state: &State) -> [f32; 3];
+    fn get_in_progress_value(&mut self, state: &State) -> Vec<f32>;
 }
 
+/// The number of players the TensorFlow value network was trained for.
+const TF_NUM_PLAYERS: usize = 3;
+
 fn get_session_data(
     filename: &str,
     num_dims: u64,
-) -> (
-    tensorflow::Session,
-    tensorflow::Tensor<f32>,
-    tensorflow::Graph,
-) {
-    let states = tensorflow::Tensor::new(&[1, 3, num_dims]);
+) -> Result<
+    (
+        tensorflow::Session,
+        tensorflow::Tensor<f32>,
+        tensorflow::Graph,
+    ),
+    GameError,
+> {
+    let model_error =
+        |err: &dyn std::fmt::Display| GameError::ModelLoad(format!("{}: {}", filename, err));
+    let states = tensorflow::Tensor::new(&[1, TF_NUM_PLAYERS as u64, num_dims]);
     let mut graph = tensorflow::Graph::new();
     let mut proto = Vec::new();
     std::fs::File::open(filename)
-        .unwrap()
-        .read_to_end(&mut proto)
-        .unwrap();
+        .and_then(|mut file| file.read_to_end(&mut proto))
+        .map_err(|err| model_error(&err))?;
     graph
         .import_graph_def(&proto, &tensorflow::ImportGraphDefOptions::new())
-        .unwrap();
-    let session = tensorflow::Session::new(&tensorflow::SessionOptions::new(), &graph).unwrap();
-    (session, states, graph)
+        .map_err(|err| model_error(&err))?;
+    let session = tensorflow::Session::new(&tensorflow::SessionOptions::new(), &graph)
+        .map_err(|err| model_error(&err))?;
+    Ok((session, states, graph))
 }
 fn run_graph(
     session: &mut tensorflow::Session,
     states: &mut tensorflow::Tensor<f32>,
     graph: &mut tensorflow::Graph,
-) -> [f32; 3] {
+) -> Vec<f32> {
     let mut args = tensorflow::SessionRunArgs::new();
     args.add_feed(
         &graph.operation_by_name_required("states").unwrap(),
@@ -47,27 +57,25 @@ fn run_graph(
     let values_idx = args.request_fetch(&graph.operation_by_name_required("values").unwrap(), 0);
     session.run(&mut args).unwrap();
     let values: tensorflow::Tensor<f32> = args.fetch(values_idx).unwrap();
-    let mut result = [0.0, 0.0, 0.0];
-    for i in 0..3 {
-        result[i] = values[i];
-    }
-    result
+    (0..TF_NUM_PLAYERS).map(|i| values[i]).collect()
 }
+/// Value network for 3-player games.
 pub struct ValueFunctionTFV2 {
     session: tensorflow::Session,
     states: tensorflow::Tensor<f32>,
     graph: tensorflow::Graph,
 }
 impl ValueFunctionTFV2 {
-    pub fn new() -> ValueFunctionTFV2 {
-        let (session, states, graph) = get_session_data("val_v2.pb", 54);
-        ValueFunctionTFV2 {
+    /// Loads the network from `val_v2.pb` in the working directory.
+    pub fn new() -> Result<ValueFunctionTFV2, GameError> {
+        let (session, states, graph) = get_session_data("val_v2.pb", 54)?;
+        Ok(ValueFunctionTFV2 {
             states,
             session,
             graph,
-        }
+        })
     }
-    fn get_value_raw(&mut self, state_arr: [[f32; 54]; 3]) -> [f32; 3] {
+    fn get_value_raw(&mut self, state_arr: [[f32; 54]; TF_NUM_PLAYERS]) -> Vec<f32> {
         let mut pos = 0;
         for player_row in state_arr.iter() {
             for val in player_row.iter() {
@@ -80,8 +88,13 @@ impl ValueFunctionTFV2 {
 }
 
 impl ValueFunction for ValueFunctionTFV2 {
-    fn get_in_progress_value(&mut self, state: &State) -> [f32; 3] {
-        let mut state_arr = [[0.0; 54], [0.0; 54], [0.0; 54]];
+    fn get_in_progress_value(&mut self, state: &State) -> Vec<f32> {
+        assert!(
+            state.num_players() as usize == TF_NUM_PLAYERS,
+            "ValueFunctionTFV2 only supports {}-player games.",
+            TF_NUM_PLAYERS
+        );
+        let mut state_arr = [[0.0; 54]; TF_NUM_PLAYERS];
         let mut min_score = std::i32::MAX;
         for board_state in state.board_states.iter() {
             if board_state.score < min_score {
@@ -125,7 +138,7 @@ impl ValueFunction for ValueFunctionTFV2 {
                 for (col_id, val) in row.iter().enumerate() {
                     if *val {
                         counts[col_id] += 1;
-                        let colour = (col_id - row_id + 5) % 5;
+                        let colour = board_state.wall_colors[row_id][col_id] as usize;
                         colour_counts[colour] += 1;
                     }
                 }
@@ -140,3 +153,34 @@ impl ValueFunction for ValueFunctionTFV2 {
         self.get_value_raw(state_arr)
     }
 }
+
+/// Estimates each player's probability of winning from the current scores alone.
+///
+/// Works for any number of players, so it is used where there is no trained network.
+pub struct ValueFunctionScore {
+    /// The score difference that makes a player e times more likely to win.
+    pub temperature: f32,
+}
+impl ValueFunctionScore {
+    pub fn new() -> ValueFunctionScore {
+        ValueFunctionScore { temperature: 5. }
+    }
+}
+
+impl ValueFunction for ValueFunctionScore {
+    fn get_in_progress_value(&mut self, state: &State) -> Vec<f32> {
+        let max_score = state
+            .board_states
+            .iter()
+            .map(|board_state| board_state.score)
+            .max()
+            .unwrap_or(0);
+        let weights: Vec<f32> = state
+            .board_states
+            .iter()
+            .map(|board_state| ((board_state.score - max_score) as f32 / self.temperature).exp())
+            .collect();
+        let sum_weights: f32 = weights.iter().sum();
+        weights.iter().map(|weight| weight / sum_weights).collect()
+    }
+}
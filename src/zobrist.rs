@@ -0,0 +1,128 @@
+//! Zobrist hashing of states, for transposition tables, evaluation caches and finding duplicate
+//! positions.
+//!
+//! Each feature of a state (a tile on a wall, the count of a color on a display, the player to
+//! play, ...) has a pseudo-random 64-bit key, and the hash of a state is the XOR of the keys of
+//! its features. `step` and `fill_factory_displays` keep `State::hash` up to date by XORing out
+//! the keys of the parts of the state they change and XORing in the new ones.
+//!
+//! The rules aren't part of the hash, so states are only comparable within a rule set.
+use crate::game_state::*;
+
+const WALL: u64 = 1;
+const ROW: u64 = 2;
+const FLOOR: u64 = 3;
+const SCORE: u64 = 4;
+const DISPLAY: u64 = 5;
+const BAG: u64 = 6;
+const LID: u64 = 7;
+const TO_PLAY: u64 = 8;
+const TILING: u64 = 9;
+const FINISHED: u64 = 10;
+
+/// The splitmix64 finaliser, which spreads each bit of the input over the whole output.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The key of a feature, given its kind and up to three small fields and a 32-bit value.
+fn key(kind: u64, a: u8, b: u8, c: u8, value: u32) -> u64 {
+    mix(kind << 56 | (a as u64) << 48 | (b as u64) << 40 | (c as u64) << 32 | value as u64)
+}
+
+/// The part of the hash for a player's board.
+pub(crate) fn board_hash(player: u8, board: &PlayerState) -> u64 {
+    let mut hash = key(SCORE, player, 0, 0, board.score as u32);
+    for row_id in 0..5 {
+        for col_id in 0..5 {
+            if board.wall_state[row_id][col_id] {
+                let color = board.wall_colors[row_id][col_id];
+                hash ^= key(WALL, player, row_id as u8, col_id as u8, color as u32);
+            }
+        }
+        let row = board.rows[row_id];
+        if row.count > 0 {
+            hash ^= key(ROW, player, row_id as u8, row.color, row.count as u32);
+        }
+    }
+    for (slot, tile) in board.floor.iter().enumerate() {
+        hash ^= key(FLOOR, player, slot as u8, *tile, 0);
+    }
+    hash
+}
+
+/// The part of the hash for a factory display or the centre, by its index.
+pub(crate) fn display_hash(display_index: usize, display: &[u8; 6]) -> u64 {
+    let mut hash = 0;
+    for (color, count) in display.iter().enumerate() {
+        if *count > 0 {
+            hash ^= key(DISPLAY, display_index as u8, color as u8, 0, *count as u32);
+        }
+    }
+    hash
+}
+
+/// The part of the hash for the bag and the lid.
+pub(crate) fn supply_hash(bag: &[u8; 5], lid: &[u8; 5]) -> u64 {
+    let mut hash = 0;
+    for color in 0..5 {
+        hash ^= key(BAG, color as u8, 0, 0, bag[color] as u32);
+        hash ^= key(LID, color as u8, 0, 0, lid[color] as u32);
+    }
+    hash
+}
+
+/// The part of the hash for whose turn it is and the stage of the game. Once the game is
+/// finished, nobody is to play.
+pub(crate) fn turn_hash(state: &State) -> u64 {
+    if state.is_finished {
+        return key(FINISHED, 0, 0, 0, 0);
+    }
+    let mut hash = key(TO_PLAY, state.player_to_play, 0, 0, 0);
+    if state.tiling {
+        hash ^= key(TILING, 0, 0, 0, 0);
+    }
+    hash
+}
+
+/// Hashes a state from scratch. `State::hash` should always equal this.
+pub fn compute_hash(state: &State) -> u64 {
+    let mut hash = turn_hash(state) ^ supply_hash(&state.bag, &state.lid);
+    for (player_id, board) in state.board_states.iter().enumerate() {
+        hash ^= board_hash(player_id as u8, board);
+    }
+    for (display_index, display) in state.central_state.central_state_arr.iter().enumerate() {
+        hash ^= display_hash(display_index, display);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::RuleSet;
+
+    /// Plays random games, checking the hash kept by `step` and `fill_factory_displays` before and
+    /// after every action.
+    fn check_random_games(free_wall: bool) {
+        let mut rules = RuleSet::default();
+        rules.free_wall = free_wall;
+        play_random_games(rules, |state, _, next_state, _| {
+            assert_eq!(state.hash, compute_hash(state));
+            assert_eq!(next_state.hash, compute_hash(next_state));
+        });
+    }
+
+    #[test]
+    fn incremental_hash_matches() {
+        check_random_games(false);
+    }
+
+    #[test]
+    fn incremental_hash_matches_free_wall() {
+        check_random_games(true);
+    }
+}